@@ -6,18 +6,50 @@
 //!
 //! from various sources and combines them.
 
-use std::fmt::Display;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
-use hyper::{body::Bytes, http, Body, Client, Request};
+use async_trait::async_trait;
+use futures::{stream::FuturesUnordered, Stream, StreamExt};
+pub use hyper::HeaderMap;
+use hyper::{
+    body::Bytes, client::HttpConnector, header, http, Body, Client, Request, StatusCode, Uri,
+};
 use hyper_tls::HttpsConnector;
 use serde::{Deserialize, Serialize};
 use tokio::{fs::File, io::AsyncReadExt};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Source<'a> {
     Http(&'a str),
     File(&'a str),
     Raw(&'a [u8]),
+    Stdin,
+}
+
+impl<'a> Source<'a> {
+    /// Resolves a user given argument into a Source.
+    ///
+    /// `http://` and `https://` prefixes are mapped to `Http`, `-` to `Stdin` and everything
+    /// else to `File`. A `file://` url is resolved to its percent-decoded path when loading.
+    pub fn from_arg(arg: &'a str) -> Self {
+        if arg.starts_with("http://") || arg.starts_with("https://") {
+            Source::Http(arg)
+        } else if arg == "-" {
+            Source::Stdin
+        } else {
+            Source::File(arg)
+        }
+    }
 }
 
 impl<'a> Display for Source<'a> {
@@ -26,18 +58,195 @@ impl<'a> Display for Source<'a> {
             Source::Http(s) => write!(f, "{s}"),
             Source::File(s) => write!(f, "{s}"),
             Source::Raw(s) => write!(f, "{}", std::str::from_utf8(s).unwrap_or_default()),
+            Source::Stdin => write!(f, "-"),
         }
     }
 }
 
-impl<'a> std::error::Error for Error {}
+/// A source of prompts in the awesome-chatgpt-prompts csv format.
+///
+/// Is implemented by `Source` and can be implemented to load prompts from other places, e.g. a
+/// database or a custom service.
+#[async_trait]
+pub trait PromptSource: Sync {
+    /// Fetches the raw csv bytes of the source.
+    async fn fetch(&self) -> Result<Vec<u8>>;
+
+    /// Fetches the raw bytes like `fetch` but with the options of http requests.
+    ///
+    /// Calls `fetch` by default, sources that are not loaded via http can ignore the options.
+    async fn fetch_with_options(&self, options: &FetchOptions) -> Result<Vec<u8>> {
+        let _ = options;
+        self.fetch().await
+    }
 
-type Result<T> = std::result::Result<T, Error>;
+    /// Fetches the raw bytes like `fetch_with_options` and returns whether they are JSON.
+    ///
+    /// Returns `false` by default so that the format is sniffed from the bytes, http sources
+    /// return true when the response is declared as JSON.
+    async fn fetch_with_format(&self, options: &FetchOptions) -> Result<(Vec<u8>, bool)> {
+        Ok((self.fetch_with_options(options).await?, false))
+    }
+
+    /// The content identifying the source for `PromptLoader::load_memoized`.
+    ///
+    /// Returns `None` by default so that the source is identified by its `Display`
+    /// representation; sources containing their bytes return them instead.
+    fn memo_key(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+#[async_trait]
+impl PromptSource for Source<'_> {
+    async fn fetch(&self) -> Result<Vec<u8>> {
+        self.fetch_with_options(&FetchOptions::default()).await
+    }
+
+    async fn fetch_with_options(&self, options: &FetchOptions) -> Result<Vec<u8>> {
+        Ok(self.fetch_with_format(options).await?.0)
+    }
+
+    async fn fetch_with_format(&self, options: &FetchOptions) -> Result<(Vec<u8>, bool)> {
+        match self {
+            Source::Http(u) => {
+                let (b, json) = PromptLoader::send(u, options).await?;
+                let b = PromptLoader::ensure_utf8(b.into(), || {
+                    format!("response is not valid UTF-8: {u}")
+                })?;
+                Ok((b, json))
+            }
+            Source::File(p) => {
+                let b = PromptLoader::load_file(p).await?;
+                let b = PromptLoader::ensure_utf8(b, || format!("file is not valid UTF-8: {p}"))?;
+                Ok((b, false))
+            }
+            Source::Raw(b) => Ok((b.to_vec(), false)),
+            Source::Stdin => Ok((PromptLoader::load_stdin().await?, false)),
+        }
+    }
+
+    fn memo_key(&self) -> Option<&[u8]> {
+        match self {
+            Source::Raw(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl<T> PromptSource for &T
+where
+    T: PromptSource + ?Sized,
+{
+    async fn fetch(&self) -> Result<Vec<u8>> {
+        (**self).fetch().await
+    }
+
+    async fn fetch_with_options(&self, options: &FetchOptions) -> Result<Vec<u8>> {
+        (**self).fetch_with_options(options).await
+    }
+
+    async fn fetch_with_format(&self, options: &FetchOptions) -> Result<(Vec<u8>, bool)> {
+        (**self).fetch_with_format(options).await
+    }
+
+    fn memo_key(&self) -> Option<&[u8]> {
+        (**self).memo_key()
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Prompt {
+    #[serde(alias = "title", alias = "name")]
     pub act: String,
+    #[serde(alias = "text", alias = "content")]
     pub prompt: String,
+    /// The optional category of the prompt, lists with two columns have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+}
+
+/// Creates a query with the prompt text as the user message.
+#[cfg(feature = "query")]
+impl From<&Prompt> for yaoaic::Query {
+    fn from(prompt: &Prompt) -> Self {
+        Self {
+            messages: vec![yaoaic::Message {
+                content: prompt.prompt.clone(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+}
+
+/// The category of prompts without a category.
+pub const UNCATEGORIZED: &str = "uncategorized";
+
+impl Prompt {
+    /// Removes leading and trailing spaces and tabs of the fields.
+    fn trimmed(self) -> Self {
+        let trim = |s: &str| s.trim_matches([' ', '\t']).to_owned();
+        Self {
+            act: trim(&self.act),
+            prompt: trim(&self.prompt),
+            category: self.category.as_deref().map(trim),
+        }
+    }
+
+    /// Returns the category or `UNCATEGORIZED` when the prompt has none.
+    pub fn category(&self) -> &str {
+        self.category.as_deref().unwrap_or(UNCATEGORIZED)
+    }
+
+    /// Replaces `{{name}}` placeholders within `prompt` with the value of `name` in `vars`.
+    ///
+    /// Placeholders without a matching variable are left as they are.
+    pub fn render(&self, vars: &HashMap<String, String>) -> String {
+        Self::substitute(&self.prompt, vars, |_| Ok(())).unwrap_or_default()
+    }
+
+    /// Like `render` but returns a `FormatError` when a placeholder has no matching variable.
+    pub fn render_strict(&self, vars: &HashMap<String, String>) -> Result<String> {
+        Self::substitute(&self.prompt, vars, |name| {
+            Err(Error::FormatError(format!(
+                "{}: no value for placeholder {{{{{name}}}}}",
+                self.act
+            )))
+        })
+    }
+
+    fn substitute(
+        template: &str,
+        vars: &HashMap<String, String>,
+        on_missing: impl Fn(&str) -> Result<()>,
+    ) -> Result<String> {
+        let mut result = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start + 2..].find("}}") else {
+                break;
+            };
+            let placeholder = &rest[start..start + len + 4];
+            let name = placeholder[2..placeholder.len() - 2].trim();
+            result.push_str(&rest[..start]);
+            match vars.get(name) {
+                Some(v) => result.push_str(v),
+                None => {
+                    on_missing(name)?;
+                    result.push_str(placeholder);
+                }
+            }
+            rest = &rest[start + len + 4..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -66,7 +275,445 @@ impl From<hyper::Error> for Error {
         Self::LoadError(value.to_string())
     }
 }
-pub struct PromptLoader {}
+
+impl From<csv::Error> for Error {
+    fn from(value: csv::Error) -> Self {
+        let msg = match value.kind() {
+            csv::ErrorKind::Deserialize {
+                pos: Some(pos),
+                err,
+            } => {
+                format!("line {}: {err}", pos.line())
+            }
+            csv::ErrorKind::Utf8 {
+                pos: Some(pos),
+                err,
+            } => {
+                format!("line {}: {err}", pos.line())
+            }
+            csv::ErrorKind::UnequalLengths {
+                pos: Some(pos),
+                expected_len,
+                len,
+            } => format!(
+                "line {}: expected {expected_len} fields but found {len}",
+                pos.line()
+            ),
+            _ => value.to_string(),
+        };
+        Self::FormatError(msg)
+    }
+}
+
+/// Splits loaded prompts into the valid prompts and the errors.
+pub fn partition_prompts(results: Vec<Result<Prompt>>) -> (Vec<Prompt>, Vec<Error>) {
+    let mut prompts = Vec::with_capacity(results.len());
+    let mut errors = vec![];
+    for r in results {
+        match r {
+            Ok(p) => prompts.push(p),
+            Err(e) => errors.push(e),
+        }
+    }
+    (prompts, errors)
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Ranks the prompts by their relevance for the given query.
+///
+/// Each query token is weighted by its inverse document frequency; a match within the act
+/// outweighs matches within the prompt, which are scored by their term frequency. Returns the
+/// indices of the matching prompts with their score, the most relevant first.
+pub fn rank_prompts(prompts: &[Prompt], query: &str) -> Vec<(usize, f32)> {
+    let query = tokenize(query);
+    let docs: Vec<_> = prompts
+        .iter()
+        .map(|p| (tokenize(&p.act), tokenize(&p.prompt)))
+        .collect();
+    let idf: Vec<_> = query
+        .iter()
+        .map(|t| {
+            let df = docs
+                .iter()
+                .filter(|(act, prompt)| act.contains(t) || prompt.contains(t))
+                .count();
+            (docs.len() as f32 / (df.max(1) as f32)).ln() + 1.0
+        })
+        .collect();
+    let mut ranked: Vec<_> = docs
+        .iter()
+        .enumerate()
+        .map(|(i, (act, prompt))| {
+            let score = query
+                .iter()
+                .zip(&idf)
+                .map(|(t, idf)| {
+                    let in_act = if act.contains(t) { 3.0 } else { 0.0 };
+                    let tf = prompt.iter().filter(|p| *p == t).count() as f32
+                        / prompt.len().max(1) as f32;
+                    idf * (in_act + tf)
+                })
+                .sum::<f32>();
+            (i, score)
+        })
+        .filter(|(_, score)| *score > 0.0)
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranked
+}
+
+/// Defines when prompts are considered duplicates.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DedupeStrategy {
+    /// Prompts with the same act.
+    #[default]
+    Act,
+    /// Prompts with the same prompt text, ignoring surrounding whitespace.
+    Content,
+    /// Prompts with either the same act or the same prompt text.
+    ActOrContent,
+}
+
+/// Removes duplicated prompts, the first prompt is kept.
+pub fn dedupe(prompts: Vec<Prompt>, strategy: DedupeStrategy) -> Vec<Prompt> {
+    let mut acts = HashSet::new();
+    let mut contents = HashSet::new();
+    prompts
+        .into_iter()
+        .filter(|p| match strategy {
+            DedupeStrategy::Act => acts.insert(p.act.clone()),
+            DedupeStrategy::Content => contents.insert(p.prompt.trim().to_owned()),
+            DedupeStrategy::ActOrContent => {
+                // only kept prompts are recorded so that a dropped one can't drop later ones
+                let keep = !acts.contains(&p.act) && !contents.contains(p.prompt.trim());
+                if keep {
+                    acts.insert(p.act.clone());
+                    contents.insert(p.prompt.trim().to_owned());
+                }
+                keep
+            }
+        })
+        .collect()
+}
+
+/// Deduplicated prompts with lookup helpers.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PromptSet {
+    prompts: Vec<Prompt>,
+}
+
+impl PromptSet {
+    /// Creates a set of the prompts without the duplicates defined by the strategy.
+    pub fn new(prompts: Vec<Prompt>, strategy: DedupeStrategy) -> Self {
+        Self {
+            prompts: dedupe(prompts, strategy),
+        }
+    }
+
+    /// Returns the prompt at the index.
+    pub fn get_by_index(&self, index: usize) -> Option<&Prompt> {
+        self.prompts.get(index)
+    }
+
+    /// Returns the first prompt with the act, ignoring the case.
+    pub fn get_by_act(&self, act: &str) -> Option<&Prompt> {
+        self.prompts
+            .iter()
+            .find(|p| p.act.eq_ignore_ascii_case(act))
+    }
+
+    /// Returns the prompt either by index, when the option is a number, or by act.
+    pub fn find(&self, option: &str) -> Option<&Prompt> {
+        match option.parse::<usize>() {
+            Ok(i) => self.get_by_index(i),
+            Err(_) => self.get_by_act(option),
+        }
+    }
+
+    /// Returns the prompts, with their index, that contain the filter in act or prompt.
+    ///
+    /// The filter is case-insensitive, an empty filter matches all prompts.
+    pub fn filter<'a>(&'a self, filter: &str) -> impl Iterator<Item = (usize, &'a Prompt)> {
+        let filter = filter.to_lowercase();
+        self.prompts.iter().enumerate().filter(move |(_, p)| {
+            p.act.to_lowercase().contains(&filter) || p.prompt.to_lowercase().contains(&filter)
+        })
+    }
+
+    /// The amount of prompts.
+    pub fn len(&self) -> usize {
+        self.prompts.len()
+    }
+
+    /// Returns true when there are no prompts.
+    pub fn is_empty(&self) -> bool {
+        self.prompts.is_empty()
+    }
+
+    /// The prompts as slice.
+    pub fn as_slice(&self) -> &[Prompt] {
+        &self.prompts
+    }
+}
+
+impl From<Vec<Prompt>> for PromptSet {
+    /// Dedupes the prompts by act.
+    fn from(prompts: Vec<Prompt>) -> Self {
+        Self::new(prompts, DedupeStrategy::default())
+    }
+}
+
+impl std::ops::Index<usize> for PromptSet {
+    type Output = Prompt;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.prompts[index]
+    }
+}
+
+impl IntoIterator for PromptSet {
+    type Item = Prompt;
+    type IntoIter = std::vec::IntoIter<Prompt>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.prompts.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a PromptSet {
+    type Item = &'a Prompt;
+    type IntoIter = std::slice::Iter<'a, Prompt>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.prompts.iter()
+    }
+}
+
+/// The differences between two prompt lists, by act.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PromptDiff {
+    /// Acts only within the other prompts.
+    pub added: Vec<String>,
+    /// Acts only within the base prompts.
+    pub removed: Vec<String>,
+    /// Acts within both but with a different prompt text.
+    pub changed: Vec<String>,
+}
+
+/// Compares the other prompts against the base prompts.
+///
+/// Prompts are matched by act, for duplicated acts the first prompt is used.
+pub fn diff_prompts(base: &[Prompt], other: &[Prompt]) -> PromptDiff {
+    let index = |prompts: &[Prompt]| {
+        let mut index = HashMap::new();
+        for p in prompts {
+            index.entry(p.act.clone()).or_insert(p.prompt.clone());
+        }
+        index
+    };
+    let (base_index, other_index) = (index(base), index(other));
+    let mut diff = PromptDiff::default();
+    for p in dedupe(base.to_vec(), DedupeStrategy::Act) {
+        match other_index.get(&p.act) {
+            None => diff.removed.push(p.act),
+            Some(prompt) if *prompt != p.prompt => diff.changed.push(p.act),
+            Some(_) => {}
+        }
+    }
+    for p in dedupe(other.to_vec(), DedupeStrategy::Act) {
+        if !base_index.contains_key(&p.act) {
+            diff.added.push(p.act);
+        }
+    }
+    diff
+}
+
+/// Writes the prompts in the awesome-chatgpt-prompts csv format.
+pub fn write_csv<W>(prompts: &[Prompt], writer: W) -> Result<()>
+where
+    W: std::io::Write,
+{
+    let mut w = csv::WriterBuilder::new()
+        .quote_style(csv::QuoteStyle::Always)
+        .from_writer(writer);
+    // the category column is only written when used to keep two column lists compatible
+    let with_category = prompts.iter().any(|p| p.category.is_some());
+    if with_category {
+        w.write_record(["act", "prompt", "category"])?;
+    } else {
+        w.write_record(["act", "prompt"])?;
+    }
+    for p in prompts {
+        if with_category {
+            w.write_record([&p.act, &p.prompt, p.category.as_deref().unwrap_or_default()])?;
+        } else {
+            w.write_record([&p.act, &p.prompt])?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Options for fetching http sources.
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// The maximum amount of followed redirects.
+    pub max_redirects: usize,
+    /// How long fetching a source may take including redirects and reading the body.
+    pub timeout: Duration,
+    /// The format requested via the `Accept` header.
+    pub accept: Accept,
+}
+
+/// The formats of prompt lists that can be requested from http sources.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Accept {
+    /// The awesome-chatgpt-prompts csv format.
+    #[default]
+    Csv,
+    /// A JSON array of prompt objects.
+    Json,
+}
+
+impl Accept {
+    /// The media type sent as `Accept` header.
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Accept::Csv => "text/csv",
+            Accept::Json => "application/json",
+        }
+    }
+}
+
+/// A fetched http response.
+#[derive(Debug)]
+struct Fetched {
+    validator: Validator,
+    /// Is true when the response declares JSON as `Content-Type`, or declares none while JSON
+    /// was requested.
+    json: bool,
+    bytes: Bytes,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            max_redirects: 5,
+            timeout: Duration::from_secs(30),
+            accept: Accept::default(),
+        }
+    }
+}
+
+/// The validators of a http response used for conditional requests.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Validator {
+    /// The `ETag` header of the response.
+    pub etag: Option<String>,
+    /// The `Last-Modified` header of the response.
+    pub last_modified: Option<String>,
+}
+
+impl Validator {
+    /// Takes the `ETag` and `Last-Modified` of the response headers.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let header_value = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &header::HeaderValue| v.to_str().ok())
+                .map(|v| v.to_owned())
+        };
+        Self {
+            etag: header_value(header::ETAG),
+            last_modified: header_value(header::LAST_MODIFIED),
+        }
+    }
+}
+
+/// The result of a conditional load.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Conditional {
+    /// The source did not change since the validator was obtained.
+    NotModified,
+    /// The source changed, contains the new prompts and validator.
+    Modified {
+        prompts: Vec<Result<Prompt>>,
+        validator: Validator,
+    },
+}
+
+/// Sets the flag when dropped.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Loads prompts from sources.
+///
+/// All loading functions are cancellation safe: dropping the returned future stops the
+/// requests as well as the parsing.
+///
+/// The associated functions load the sources on each call, a loader created by `with_memo`
+/// additionally keeps loaded prompts in memory, see `load_memoized`.
+#[derive(Default)]
+pub struct PromptLoader {
+    memo: Option<Memo>,
+}
+
+/// The in-memory results of loaded source sets, identified by the sources.
+struct Memo {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<Vec<MemoKey>, MemoEntry>>,
+}
+
+/// Identifies a source within the memo, see `PromptSource::memo_key`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum MemoKey {
+    Display(String),
+    Bytes(Vec<u8>),
+}
+
+/// When the prompts were loaded and the loaded prompts.
+type MemoEntry = (Instant, Vec<Result<Prompt>>);
+
+impl Memo {
+    fn get(&self, key: &[MemoKey]) -> Option<Vec<Result<Prompt>>> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(key)
+            .filter(|(loaded, _)| loaded.elapsed() < self.ttl)
+            .map(|(_, prompts)| prompts.clone())
+    }
+
+    /// Stores the prompts, when full expired and then the oldest entries are evicted.
+    fn insert(&self, key: Vec<MemoKey>, prompts: Vec<Result<Prompt>>) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, (loaded, _)| loaded.elapsed() < self.ttl);
+        while entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (loaded, _))| *loaded)
+                .map(|(k, _)| k.clone());
+            match oldest {
+                Some(k) => entries.remove(&k),
+                None => break,
+            };
+        }
+        if self.capacity > 0 {
+            entries.insert(key, (Instant::now(), prompts));
+        }
+    }
+}
 
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
@@ -75,65 +722,663 @@ impl From<std::io::Error> for Error {
 }
 
 impl PromptLoader {
-    async fn parse_bytes(b: Vec<u8>) -> Vec<Result<Prompt>> {
-        let result = tokio::task::spawn_blocking(move || {
-            let mut cr = csv::Reader::from_reader(&b as &[u8]);
-            cr.deserialize()
-                .map(|e| e.map_err(|e| Error::FormatError(e.to_string())))
-                .collect()
+    /// Creates a loader that keeps the prompts of up to `capacity` source sets for `ttl` in
+    /// memory.
+    pub fn with_memo(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            memo: Some(Memo {
+                ttl,
+                capacity,
+                entries: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Loads the given sources like `load` but reuses results of the same sources that are
+    /// younger than the ttl of the memo.
+    ///
+    /// Sources are identified by their `PromptSource::memo_key` or else by their `Display`
+    /// representation. Without memo, e.g. for
+    /// `PromptLoader::default()`, the sources are loaded on each call.
+    pub async fn load_memoized<S>(&self, sources: &[S]) -> Vec<Result<Prompt>>
+    where
+        S: PromptSource + Display,
+    {
+        let Some(memo) = &self.memo else {
+            return Self::load(sources).await;
+        };
+        let key: Vec<MemoKey> = sources
+            .iter()
+            .map(|s| match s.memo_key() {
+                Some(b) => MemoKey::Bytes(b.to_vec()),
+                None => MemoKey::Display(s.to_string()),
+            })
+            .collect();
+        if let Some(prompts) = memo.get(&key) {
+            return prompts;
+        }
+        let prompts = Self::load(sources).await;
+        memo.insert(key, prompts.clone());
+        prompts
+    }
+
+    /// Returns an `Error::LoadError` with the message of `err` when b is not valid UTF-8.
+    ///
+    /// With the `encoding` feature enabled the bytes are transcoded by `decode` instead.
+    fn ensure_utf8<F>(b: Vec<u8>, err: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> String,
+    {
+        if cfg!(feature = "encoding") || std::str::from_utf8(&b).is_ok() {
+            Ok(b)
+        } else {
+            Err(Error::LoadError(err()))
+        }
+    }
+
+    /// Removes a leading UTF-8 byte order mark.
+    ///
+    /// With the `encoding` feature enabled UTF-16 (detected via BOM) and other non UTF-8 input
+    /// (assumed to be Windows-1252/Latin-1) are transcoded to UTF-8.
+    fn decode(b: &[u8]) -> Cow<'_, [u8]> {
+        #[cfg(feature = "encoding")]
+        {
+            use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+            if let Some((enc, bom_len)) = Encoding::for_bom(b) {
+                if enc != UTF_8 {
+                    let (decoded, _) = enc.decode_without_bom_handling(&b[bom_len..]);
+                    return Cow::Owned(decoded.into_owned().into_bytes());
+                }
+            } else if std::str::from_utf8(b).is_err() {
+                let (decoded, _) = WINDOWS_1252.decode_without_bom_handling(b);
+                return Cow::Owned(decoded.into_owned().into_bytes());
+            }
+        }
+        Cow::Borrowed(b.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(b))
+    }
+
+    /// Parses csv bytes in the awesome-chatgpt-prompts format.
+    ///
+    /// This is the parser used for all sources and can be used for bytes obtained otherwise.
+    /// Leading and trailing spaces and tabs of the fields are trimmed, newlines are kept.
+    pub fn parse(b: &[u8]) -> Vec<Result<Prompt>> {
+        Self::parse_csv(b, None, None, true, &AtomicBool::new(false))
+    }
+
+    /// Parses a JSON array of prompt objects, e.g. `[{"act": "...", "prompt": "..."}]`, or a
+    /// single prompt object.
+    ///
+    /// The fields are trimmed like by `parse`. Sources starting with `[` or `{` are parsed by
+    /// this function instead of as csv.
+    ///
+    /// Like the rows of a csv, each element of an array is converted on its own so that a
+    /// malformed element is returned as error with its index while the others are kept.
+    pub fn parse_json(b: &[u8]) -> Vec<Result<Prompt>> {
+        let prompt = |v| serde_json::from_value::<Prompt>(v).map(Prompt::trimmed);
+        let b = b.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(b);
+        match serde_json::from_slice::<serde_json::Value>(b) {
+            Ok(serde_json::Value::Array(elements)) => elements
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| {
+                    prompt(v).map_err(|e| Error::FormatError(format!("element {i}: {e}")))
+                })
+                .collect(),
+            Ok(v) => vec![prompt(v).map_err(|e| Error::FormatError(e.to_string()))],
+            Err(e) => vec![Err(Error::FormatError(format!("line {}: {e}", e.line())))],
+        }
+    }
+
+    /// Parses csv bytes like `parse` but keeps the spaces around the fields.
+    pub fn parse_untrimmed(b: &[u8]) -> Vec<Result<Prompt>> {
+        Self::parse_csv(b, None, None, false, &AtomicBool::new(false))
+    }
+
+    /// Parses csv bytes that use other column names than `act` and `prompt`.
+    ///
+    /// Besides the given names the common alternatives `title`, `name` and `text`, `content`
+    /// are always accepted.
+    pub fn parse_with_columns(b: &[u8], act_col: &str, prompt_col: &str) -> Vec<Result<Prompt>> {
+        Self::parse_csv(
+            b,
+            None,
+            Some((act_col, prompt_col)),
+            true,
+            &AtomicBool::new(false),
+        )
+    }
+
+    /// Parses the csv bytes until `max_prompts` are found or `cancelled` is set.
+    ///
+    /// `columns` renames the given act and prompt columns, `trim` trims spaces around the fields.
+    fn parse_csv(
+        b: &[u8],
+        max_prompts: Option<usize>,
+        columns: Option<(&str, &str)>,
+        trim: bool,
+        cancelled: &AtomicBool,
+    ) -> Vec<Result<Prompt>> {
+        let b = Self::decode(b);
+        let mut cr = csv::Reader::from_reader(&b as &[u8]);
+        let headers = match cr.headers() {
+            Ok(h) => match columns {
+                Some((act, prompt)) => h
+                    .iter()
+                    .map(|c| match c {
+                        c if c == act => "act",
+                        c if c == prompt => "prompt",
+                        c => c,
+                    })
+                    .collect(),
+                None => h.clone(),
+            },
+            Err(e) => return vec![Err(e.into())],
+        };
+        let records = cr
+            .records()
+            // records without any content (e.g. `,` or `"",""`) are skipped silently
+            .filter(|r| match r {
+                Ok(r) => r.iter().any(|f| !f.trim().is_empty()),
+                Err(_) => true,
+            })
+            .map(|r| {
+                r.and_then(|r| r.deserialize(Some(&headers)))
+                    .map(|p: Prompt| if trim { p.trimmed() } else { p })
+                    .map_err(Into::into)
+            });
+        let mut result = vec![];
+        let mut prompts = 0;
+        for r in records {
+            if cancelled.load(Ordering::Relaxed) {
+                break;
+            }
+            if max_prompts.map(|m| prompts >= m).unwrap_or_default() {
+                break;
+            }
+            if r.is_ok() {
+                prompts += 1;
+            }
+            result.push(r);
+        }
+        result
+    }
+
+    /// Returns true when the bytes start with a JSON array or object instead of a csv header.
+    fn is_json(b: &[u8]) -> bool {
+        let b = b.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(b);
+        matches!(b.trim_ascii_start().first(), Some(b'[' | b'{'))
+    }
+
+    /// Parses the bytes as JSON when `json` is set or they look like JSON, otherwise as csv.
+    async fn parse_bytes(
+        b: Vec<u8>,
+        json: bool,
+        max_prompts: Option<usize>,
+    ) -> Vec<Result<Prompt>> {
+        // a blocking task is not aborted when the future is dropped so it is signaled instead
+        let cancelled = CancelOnDrop(Arc::new(AtomicBool::new(false)));
+        let flag = cancelled.0.clone();
+        tokio::task::spawn_blocking(move || {
+            if json || Self::is_json(&b) {
+                let mut prompts = Self::parse_json(&b);
+                prompts.truncate(max_prompts.unwrap_or(usize::MAX));
+                prompts
+            } else {
+                Self::parse_csv(&b, max_prompts, None, true, &flag)
+            }
         })
         .await
-        .unwrap_or_default();
-        result
+        .unwrap_or_default()
+    }
+
+    /// Returns the path of a `file://` url or the given path when it is no url.
+    fn file_path(p: &str) -> Result<Cow<'_, str>> {
+        let Some(url) = p.strip_prefix("file://") else {
+            return Ok(Cow::Borrowed(p));
+        };
+        let path = match url.strip_prefix("localhost") {
+            Some(path) => path,
+            None => url,
+        };
+        if !path.starts_with('/') {
+            return Err(Error::LoadError(format!(
+                "{p}: only local absolute file urls are supported"
+            )));
+        }
+        let mut decoded = Vec::with_capacity(path.len());
+        let mut bytes = path.bytes();
+        while let Some(b) = bytes.next() {
+            if b != b'%' {
+                decoded.push(b);
+                continue;
+            }
+            let hex = [bytes.next(), bytes.next()];
+            let byte = match hex {
+                [Some(h), Some(l)] => std::str::from_utf8(&[h, l])
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok()),
+                _ => None,
+            };
+            match byte {
+                Some(byte) => decoded.push(byte),
+                None => return Err(Error::LoadError(format!("{p}: invalid percent-encoding"))),
+            }
+        }
+        String::from_utf8(decoded)
+            .map(Cow::Owned)
+            .map_err(|_| Error::LoadError(format!("{p}: path is not valid UTF-8")))
     }
 
     async fn load_file(p: &str) -> Result<Vec<u8>> {
-        let mut file = File::open(p).await?;
+        let mut file = File::open(Self::file_path(p)?.as_ref()).await?;
         let mut contents = vec![];
         file.read_to_end(&mut contents).await?;
         Ok(contents)
     }
 
-    async fn parse_source(source: &Source<'_>) -> Vec<Result<Prompt>> {
-        match source {
-            Source::Http(u) => match Self::send(u).await {
-                Ok(b) => Self::parse_bytes(b.into()).await,
-                Err(e) => vec![Err(e)],
+    async fn load_stdin() -> Result<Vec<u8>> {
+        let mut contents = vec![];
+        tokio::io::stdin().read_to_end(&mut contents).await?;
+        Ok(contents)
+    }
+
+    async fn parse_source<S>(
+        source: &S,
+        max_prompts: Option<usize>,
+        options: &FetchOptions,
+    ) -> Vec<Result<Prompt>>
+    where
+        S: PromptSource + ?Sized,
+    {
+        match source.fetch_with_format(options).await {
+            Ok((b, json)) => Self::parse_bytes(b, json, max_prompts).await,
+            Err(e) => vec![Err(e)],
+        }
+    }
+
+    /// Fetches src and returns the body with whether it is JSON, see `Fetched::json`.
+    async fn send(src: &str, options: &FetchOptions) -> Result<(Bytes, bool)> {
+        let fetched = Self::fetch(src, &Validator::default(), options).await?;
+        Ok(fetched.map(|f| (f.bytes, f.json)).unwrap_or_default())
+    }
+
+    /// Sends a GET request to src with the conditional headers of the validator.
+    ///
+    /// Redirects are followed up to `FetchOptions::max_redirects` hops, a redirect to an already
+    /// visited url is an error. Returns `None` when the server responds with `304 Not Modified`.
+    async fn fetch(
+        src: &str,
+        validator: &Validator,
+        options: &FetchOptions,
+    ) -> Result<Option<Fetched>> {
+        let fetched = Self::fetch_response(src, validator, options).await?;
+        if let Some((headers, b)) = &fetched {
+            if Self::is_html(headers, b) {
+                return Err(Error::LoadError(format!(
+                    "expected {} but got HTML from {src}",
+                    options.accept.media_type()
+                )));
+            }
+        }
+        Ok(fetched.map(|(headers, bytes)| Fetched {
+            validator: Validator::from_headers(&headers),
+            json: match headers.get(header::CONTENT_TYPE) {
+                Some(c) => c
+                    .to_str()
+                    .is_ok_and(|c| c.to_ascii_lowercase().contains("json")),
+                None => options.accept == Accept::Json,
             },
-            Source::File(p) => match Self::load_file(p).await {
-                Ok(b) => Self::parse_bytes(b).await,
-                Err(e) => {
-                    vec![Err(e)]
+            bytes,
+        }))
+    }
+
+    /// Returns true when the response is declared or sniffed as html, e.g. a login page.
+    fn is_html(headers: &HeaderMap, b: &[u8]) -> bool {
+        let declared = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|c| c.to_str().ok())
+            .is_some_and(|c| c.trim_start().to_ascii_lowercase().starts_with("text/html"));
+        let start = b.trim_ascii_start();
+        let sniffed = [&b"<!doctype html"[..], b"<html"].iter().any(|tag| {
+            start
+                .get(..tag.len())
+                .is_some_and(|s| s.eq_ignore_ascii_case(tag))
+        });
+        declared || sniffed
+    }
+
+    /// Fetches an http source and returns the headers of the final response with the body.
+    ///
+    /// The headers allow e.g. to verify the `Content-Type` before parsing the body.
+    pub async fn fetch_with_headers(
+        src: &str,
+        options: &FetchOptions,
+    ) -> Result<(HeaderMap, Bytes)> {
+        let fetched = Self::fetch_response(src, &Validator::default(), options).await?;
+        Ok(fetched.unwrap_or_default())
+    }
+
+    /// Sends the request with the timeout of the options, see `fetch`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(url = %src)))]
+    async fn fetch_response(
+        src: &str,
+        validator: &Validator,
+        options: &FetchOptions,
+    ) -> Result<Option<(HeaderMap, Bytes)>> {
+        tokio::time::timeout(
+            options.timeout,
+            Self::fetch_redirected(src, validator, options),
+        )
+        .await
+        .map_err(|_| Error::LoadError(format!("{src}: timed out after {:?}", options.timeout)))?
+    }
+
+    /// Fetches src and follows the redirects, see `fetch`.
+    async fn fetch_redirected(
+        src: &str,
+        validator: &Validator,
+        options: &FetchOptions,
+    ) -> Result<Option<(HeaderMap, Bytes)>> {
+        let client = Client::builder().build(connector());
+        let mut url: Uri = src
+            .parse()
+            .map_err(|e| Error::LoadError(format!("{src}: {e}")))?;
+        let mut visited = HashSet::new();
+        let res = loop {
+            let mut req =
+                Request::get(url.clone()).header(header::ACCEPT, options.accept.media_type());
+            if let Some(etag) = &validator.etag {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validator.last_modified {
+                req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+            let res = client.request(req.body(Body::empty())?).await?;
+            #[cfg(feature = "tracing")]
+            tracing::debug!(status = %res.status(), %url, "received response");
+            if res.status() == StatusCode::NOT_MODIFIED || !res.status().is_redirection() {
+                break res;
+            }
+            let Some(location) = res.headers().get(header::LOCATION) else {
+                break res;
+            };
+            visited.insert(url.to_string());
+            if visited.len() > options.max_redirects {
+                return Err(Error::LoadError("too many redirects".into()));
+            }
+            url = Self::resolve_location(&url, location)?;
+            if visited.contains(&url.to_string()) {
+                return Err(Error::LoadError(format!("redirect loop at {url}")));
+            }
+        };
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let (parts, body) = res.into_parts();
+        let content_length: Option<usize> = parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|l| l.to_str().ok()?.parse().ok());
+        let truncated = || Error::LoadError("truncated response".into());
+        let bytes = hyper::body::to_bytes(body).await.map_err(|e| {
+            // hyper reports a body shorter than its content length as unexpected eof
+            let eof = std::error::Error::source(&e)
+                .and_then(|s| s.downcast_ref::<std::io::Error>())
+                .is_some_and(|s| s.kind() == std::io::ErrorKind::UnexpectedEof);
+            match e.is_incomplete_message() || eof {
+                true => truncated(),
+                false => e.into(),
+            }
+        })?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(bytes = bytes.len(), content_length, "read response body");
+        // a partial csv would otherwise silently parse into the first prompts
+        if content_length.is_some_and(|l| l != bytes.len()) {
+            return Err(truncated());
+        }
+        Ok(Some((parts.headers, bytes)))
+    }
+
+    /// Resolves the location of a redirect, relative locations are based on the current url.
+    ///
+    /// A relative path is resolved against the directory of the current path, as described
+    /// in RFC 3986, section 5.2.
+    fn resolve_location(current: &Uri, location: &header::HeaderValue) -> Result<Uri> {
+        let location = location
+            .to_str()
+            .map_err(|e| Error::LoadError(format!("invalid redirect location: {e}")))?;
+        let invalid = |e: http::Error| Error::LoadError(format!("{location}: {e}"));
+        if let Ok(target) = location.parse::<Uri>() {
+            if target.scheme().is_some() {
+                return Ok(target);
+            }
+        }
+        let reference = location.split('#').next().unwrap_or_default();
+        let (path, query) = match reference.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (reference, None),
+        };
+        let (path, query) = if path.starts_with('/') {
+            (Self::remove_dot_segments(path), query)
+        } else if path.is_empty() {
+            (current.path().to_owned(), query.or(current.query()))
+        } else {
+            let base = current.path();
+            let dir = &base[..base.rfind('/').map_or(0, |i| i + 1)];
+            (Self::remove_dot_segments(&format!("{dir}{path}")), query)
+        };
+        let path_and_query = match query {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        };
+        let mut parts = current.clone().into_parts();
+        parts.path_and_query = Some(
+            path_and_query
+                .parse()
+                .map_err(|e: http::uri::InvalidUri| invalid(e.into()))?,
+        );
+        Uri::from_parts(parts).map_err(|e| invalid(e.into()))
+    }
+
+    /// Removes the `.` and `..` segments of an absolute path.
+    fn remove_dot_segments(path: &str) -> String {
+        let segments: Vec<_> = path.split('/').skip(1).collect();
+        let mut resolved = Vec::with_capacity(segments.len());
+        for (i, segment) in segments.iter().enumerate() {
+            let last = i + 1 == segments.len();
+            match *segment {
+                "." => {}
+                ".." => {
+                    resolved.pop();
                 }
-            },
-            Source::Raw(b) => Self::parse_bytes(b.into_iter().map(|b| *b).collect()).await,
+                segment => {
+                    resolved.push(segment);
+                    continue;
+                }
+            }
+            if last {
+                resolved.push("");
+            }
         }
+        format!("/{}", resolved.join("/"))
     }
 
-    async fn send(src: &str) -> Result<Bytes> {
-        let req = Request::get(src).body(Body::empty())?;
-        let https = HttpsConnector::new();
-        let client = Client::builder().build(https);
-        let res = client.request(req).await?;
-        hyper::body::to_bytes(res.into_body())
-            .await
-            .map_err(|e| e.into())
+    /// Loads the prompts of an http source unless they are unchanged.
+    ///
+    /// The `ETag` and `Last-Modified` of the validator are sent as `If-None-Match` and
+    /// `If-Modified-Since`. When the server responds with `304 Not Modified`
+    /// `Conditional::NotModified` is returned and the previously loaded prompts can be used.
+    pub async fn load_if_modified(
+        url: &str,
+        validator: &Validator,
+        max_prompts: Option<usize>,
+        options: &FetchOptions,
+    ) -> Result<Conditional> {
+        Ok(match Self::fetch(url, validator, options).await? {
+            None => Conditional::NotModified,
+            Some(f) => {
+                let b = Self::ensure_utf8(f.bytes.into(), || {
+                    format!("response is not valid UTF-8: {url}")
+                })?;
+                Conditional::Modified {
+                    prompts: Self::parse_bytes(b, f.json, max_prompts).await,
+                    validator: f.validator,
+                }
+            }
+        })
     }
 
-    pub async fn load<'a>(sources: &[Source<'_>]) -> Vec<Result<Prompt>> {
+    /// Loads the given sources in order.
+    ///
+    /// Besides `Source` any `PromptSource` can be used, e.g. `&[&dyn PromptSource]` to mix
+    /// custom sources with the built-in ones.
+    pub async fn load<S>(sources: &[S]) -> Vec<Result<Prompt>>
+    where
+        S: PromptSource,
+    {
+        Self::load_max(sources, None).await
+    }
+
+    /// Loads the given sources in order and blocks until all are loaded.
+    ///
+    /// The sources are loaded on a new current thread runtime, therefore this must not be called
+    /// from within an async context.
+    #[cfg(feature = "blocking")]
+    pub fn load_blocking<S>(sources: &[S]) -> Result<Vec<Result<Prompt>>>
+    where
+        S: PromptSource,
+    {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(rt.block_on(Self::load(sources)))
+    }
+
+    /// Loads the given sources but stops parsing a source after `max_prompts` valid prompts.
+    ///
+    /// When `max_prompts` is `None` all prompts are loaded.
+    pub async fn load_max<S>(sources: &[S], max_prompts: Option<usize>) -> Vec<Result<Prompt>>
+    where
+        S: PromptSource,
+    {
+        Self::load_with_options(sources, max_prompts, &FetchOptions::default()).await
+    }
+
+    /// Loads the given sources like `load_max` with the options of http requests, e.g. to accept
+    /// JSON or to change the timeout.
+    pub async fn load_with_options<S>(
+        sources: &[S],
+        max_prompts: Option<usize>,
+        options: &FetchOptions,
+    ) -> Vec<Result<Prompt>>
+    where
+        S: PromptSource,
+    {
         let mut result = Vec::new();
         for s in sources {
-            let b = Self::parse_source(s).await;
+            let b = Self::parse_source(s, max_prompts, options).await;
             result.extend(b);
         }
         result
     }
+
+    /// Loads the given sources in order and fails on the first load or parse error.
+    ///
+    /// Unlike `load`, which returns the errors alongside the prompts, this is meant for
+    /// validating prompt files, e.g. within CI.
+    pub async fn load_strict<S>(sources: &[S]) -> Result<Vec<Prompt>>
+    where
+        S: PromptSource,
+    {
+        let mut prompts = vec![];
+        for s in sources {
+            for p in Self::parse_source(s, None, &FetchOptions::default()).await {
+                prompts.push(p?);
+            }
+        }
+        Ok(prompts)
+    }
+
+    /// Returns the valid prompts of the first source that yields any, the other sources are not
+    /// loaded.
+    ///
+    /// Unlike `load` the sources are not combined, e.g. a local file fully replaces a remote
+    /// fallback. When no source yields a valid prompt the last error is returned.
+    pub async fn load_first_ok<S>(sources: &[S]) -> Result<Vec<Prompt>>
+    where
+        S: PromptSource,
+    {
+        let mut last_error = Error::LoadError("no source yields valid prompts".into());
+        for s in sources {
+            let options = FetchOptions::default();
+            let (prompts, errors) = partition_prompts(Self::parse_source(s, None, &options).await);
+            if !prompts.is_empty() {
+                return Ok(prompts);
+            }
+            if let Some(e) = errors.into_iter().last() {
+                last_error = e;
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Loads all given sources concurrently and yields the prompts as soon as a source finished.
+    ///
+    /// Unlike `load` the prompts are in completion order of the sources.
+    pub fn load_stream<S>(sources: &[S]) -> impl Stream<Item = Result<Prompt>> + '_
+    where
+        S: PromptSource,
+    {
+        sources
+            .iter()
+            .map(|s| async move { Self::parse_source(s, None, &FetchOptions::default()).await })
+            .collect::<FuturesUnordered<_>>()
+            .flat_map(futures::stream::iter)
+    }
+}
+
+/// Returns the https connector used by every `PromptLoader` fetch.
+///
+/// `PromptLoader` has no state to keep a client in, so the connector is kept in a static instead
+/// of initializing TLS again for each source.
+fn connector() -> HttpsConnector<HttpConnector> {
+    static CONNECTOR: OnceLock<HttpsConnector<HttpConnector>> = OnceLock::new();
+    CONNECTOR.get_or_init(HttpsConnector::new).clone()
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::AtomicUsize;
+
     use super::*;
 
+    fn prompt(act: &str, text: &str) -> Prompt {
+        Prompt {
+            act: act.into(),
+            prompt: text.into(),
+            category: None,
+        }
+    }
+
+    /// Answers the first request to the returned url with the raw http response.
+    ///
+    /// The handle resolves to the received request, e.g. to verify the sent headers.
+    async fn serve_once(response: impl Into<Vec<u8>>) -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::AsyncWriteExt;
+
+        let response = response.into();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let request = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket.write_all(&response).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        });
+        (url, request)
+    }
+
     #[tokio::test]
     async fn parse() {
         let example = r###"
@@ -146,12 +1391,642 @@ mod tests {
             Ok(Prompt {
                 act: "1".into(),
                 prompt: "1".into(),
+                category: None,
             }),
             Ok(Prompt {
                 act: "2".into(),
                 prompt: "2".into(),
+                category: None,
             }),
         ];
         assert_eq!(result, expected);
     }
+
+    #[tokio::test]
+    async fn load_stream() {
+        let first = "\"act\",\"prompt\"\n\"1\",\"1\"\n";
+        let second = "\"act\",\"prompt\"\n\"2\",\"2\"\n";
+        let sources = [
+            Source::Raw(first.as_bytes()),
+            Source::Raw(second.as_bytes()),
+        ];
+        let mut result: Vec<_> = PromptLoader::load_stream(&sources)
+            .map(|p| p.map(|p| p.act).unwrap_or_default())
+            .collect()
+            .await;
+        result.sort();
+        assert_eq!(result, vec!["1".to_string(), "2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn multi_line_prompt() {
+        let example = r###""act","prompt"
+"List","Do the following:
+1. read
+
+2. answer ""briefly""
+"
+"Single","single"
+"###;
+        let result = PromptLoader::load(&[Source::Raw(example.as_bytes())]).await;
+        let expected = vec![
+            Ok(Prompt {
+                act: "List".into(),
+                prompt: "Do the following:\n1. read\n\n2. answer \"briefly\"\n".into(),
+                category: None,
+            }),
+            Ok(Prompt {
+                act: "Single".into(),
+                prompt: "single".into(),
+                category: None,
+            }),
+        ];
+        assert_eq!(result, expected);
+        let crlf = example.replace('\n', "\r\n");
+        let result = PromptLoader::parse(crlf.as_bytes());
+        assert_eq!(
+            result[0].as_ref().map(|p| p.prompt.as_str()),
+            Ok("Do the following:\r\n1. read\r\n\r\n2. answer \"briefly\"\r\n")
+        );
+    }
+
+    #[test]
+    fn rank_prompts() {
+        let prompts = vec![
+            prompt(
+                "Shell Expert",
+                "Explain terminal commands, the terminal is bash.",
+            ),
+            prompt("Poet", "Write poems."),
+            prompt("Linux Terminal", "Reply with the output of commands."),
+        ];
+        let ranked = super::rank_prompts(&prompts, "terminal");
+        assert_eq!(
+            ranked.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![2, 0]
+        );
+    }
+
+    #[test]
+    fn file_path() {
+        assert_eq!(PromptLoader::file_path("a b.csv").unwrap(), "a b.csv");
+        assert_eq!(
+            PromptLoader::file_path("file:///tmp/a%20b.csv").unwrap(),
+            "/tmp/a b.csv"
+        );
+        assert_eq!(
+            PromptLoader::file_path("file://localhost/tmp/a.csv").unwrap(),
+            "/tmp/a.csv"
+        );
+        assert!(PromptLoader::file_path("file://host/tmp/a.csv").is_err());
+        assert!(PromptLoader::file_path("file:///tmp/a%2.csv").is_err());
+    }
+
+    #[test]
+    fn dedupe_by_content() {
+        let prompts = vec![
+            prompt("A", "same"),
+            prompt("B", " same\n"),
+            prompt("A", "other"),
+        ];
+        let acts = |p: Vec<Prompt>| p.into_iter().map(|p| p.act).collect::<Vec<_>>();
+        assert_eq!(
+            acts(dedupe(prompts.clone(), DedupeStrategy::Content)),
+            vec!["A", "A"]
+        );
+        assert_eq!(
+            acts(dedupe(prompts, DedupeStrategy::ActOrContent)),
+            vec!["A"]
+        );
+        let prompts = vec![prompt("A", "x"), prompt("A", "y"), prompt("B", "y")];
+        assert_eq!(
+            acts(dedupe(prompts, DedupeStrategy::ActOrContent)),
+            vec!["A", "B"]
+        );
+    }
+
+    #[test]
+    fn prompt_set() {
+        let set = PromptSet::from(vec![
+            prompt("Linux Terminal", "act as terminal"),
+            prompt("linux terminal", "duplicate"),
+            prompt("Poet", "write poems"),
+        ]);
+        assert_eq!(set.len(), 3);
+        assert_eq!(
+            set.find("1").map(|p| p.act.as_str()),
+            Some("linux terminal")
+        );
+        assert_eq!(set.find("LINUX TERMINAL"), Some(&set[0]));
+        assert_eq!(
+            set.filter("POEM").map(|(i, _)| i).collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn diff_prompts() {
+        let remote = [prompt("A", "a"), prompt("B", "b"), prompt("C", "c")];
+        let local = [prompt("B", "b"), prompt("C", "changed"), prompt("D", "d")];
+        assert_eq!(
+            super::diff_prompts(&remote, &local),
+            PromptDiff {
+                added: vec!["D".into()],
+                removed: vec!["A".into()],
+                changed: vec!["C".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn other_columns() {
+        let expected = vec![Ok(Prompt {
+            act: "a".into(),
+            prompt: "p".into(),
+            category: None,
+        })];
+        assert_eq!(PromptLoader::parse(b"title,text\na,p\n"), expected);
+        assert_eq!(
+            PromptLoader::parse_with_columns(
+                b"Persona,Instruction\na,p\n",
+                "Persona",
+                "Instruction"
+            ),
+            expected
+        );
+    }
+
+    #[cfg(feature = "query")]
+    #[test]
+    fn into_query() {
+        let p = Prompt {
+            act: "a".into(),
+            prompt: "p".into(),
+            category: None,
+        };
+        let q = yaoaic::Query::from(&p);
+        assert_eq!(q.messages.len(), 1);
+        assert_eq!(q.messages[0].role, "user");
+        assert_eq!(q.messages[0].content, "p");
+    }
+
+    #[cfg(not(feature = "encoding"))]
+    #[test]
+    fn invalid_utf8() {
+        let latin1 = b"act,prompt\nCaf\xe9,p\n".to_vec();
+        assert_eq!(
+            PromptLoader::ensure_utf8(latin1, || "file is not valid UTF-8: a.csv".into()),
+            Err(Error::LoadError("file is not valid UTF-8: a.csv".into()))
+        );
+    }
+
+    #[cfg(not(feature = "encoding"))]
+    #[tokio::test]
+    async fn load_if_modified_invalid_utf8() {
+        let body = b"act,prompt\nCaf\xe9,p\nTh\xe9,q\n";
+        let mut response =
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        response.extend(body);
+        let (url, _) = serve_once(response).await;
+        let err = PromptLoader::load_if_modified(
+            &url,
+            &Validator::default(),
+            None,
+            &FetchOptions::default(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err,
+            Error::LoadError(format!("response is not valid UTF-8: {url}"))
+        );
+    }
+
+    #[tokio::test]
+    async fn load_memoized() {
+        struct Counted(AtomicUsize);
+
+        #[async_trait]
+        impl PromptSource for Counted {
+            async fn fetch(&self) -> Result<Vec<u8>> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Ok(b"act,prompt\na,p\n".to_vec())
+            }
+        }
+
+        impl Display for Counted {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "counted")
+            }
+        }
+
+        let source = [Counted(AtomicUsize::new(0))];
+        let loader = PromptLoader::with_memo(Duration::from_secs(60), 1);
+        assert_eq!(loader.load_memoized(&source).await.len(), 1);
+        assert_eq!(loader.load_memoized(&source).await.len(), 1);
+        assert_eq!(source[0].0.load(Ordering::Relaxed), 1);
+        PromptLoader::default().load_memoized(&source).await;
+        assert_eq!(source[0].0.load(Ordering::Relaxed), 2);
+
+        // both are displayed as empty string
+        let loader = PromptLoader::with_memo(Duration::from_secs(60), 2);
+        let a = [Source::Raw(b"act,prompt\n\xe9,a\n")];
+        let b = [Source::Raw(b"act,prompt\nb,b\n\xe9,b\n")];
+        assert_eq!(a[0].to_string(), b[0].to_string());
+        let expected = PromptLoader::load(&b).await;
+        assert_ne!(loader.load_memoized(&a).await, expected);
+        assert_eq!(loader.load_memoized(&b).await, expected);
+    }
+
+    #[tokio::test]
+    async fn json_source() {
+        let array = Source::Raw(
+            b" [{\"act\": \"a\", \"prompt\": \"p\"}, {\"title\": \"b\", \"text\": \"q\"}]",
+        );
+        let object = Source::Raw(b"{\"act\": \"c\", \"prompt\": \"r\"}");
+        let prompts = PromptLoader::load_strict(&[array, object]).await.unwrap();
+        let acts: Vec<_> = prompts.iter().map(|p| p.act.as_str()).collect();
+        assert_eq!(acts, vec!["a", "b", "c"]);
+        let malformed = PromptLoader::load(&[Source::Raw(b"[{\"act\": 1}]")]).await;
+        assert!(matches!(malformed[..], [Err(Error::FormatError(_))]));
+    }
+
+    #[test]
+    fn parse_json_per_element() {
+        let prompts = PromptLoader::parse_json(
+            br#"[{"act": "a", "prompt": "p"}, {"act": 1}, {"act": " b ", "prompt": "q"}]"#,
+        );
+        assert_eq!(prompts.len(), 3);
+        assert_eq!(prompts[0], Ok(prompt("a", "p")));
+        assert!(
+            matches!(&prompts[1], Err(Error::FormatError(e)) if e.starts_with("element 1: ")),
+            "{:?}",
+            prompts[1]
+        );
+        assert_eq!(prompts[2], Ok(prompt("b", "q")));
+    }
+
+    #[tokio::test]
+    async fn load_strict() {
+        let valid = Source::Raw(b"act,prompt\na,p\n");
+        let prompts = PromptLoader::load_strict(&[valid]).await.unwrap();
+        assert_eq!(prompts.len(), 1);
+        let malformed = Source::Raw(b"act,prompt\na,p\nb\n");
+        let result = PromptLoader::load_strict(&[valid, malformed]).await;
+        assert!(matches!(result, Err(Error::FormatError(_))));
+    }
+
+    #[tokio::test]
+    async fn load_first_ok() {
+        let sources = [
+            Source::File("/does/not/exist.csv"),
+            Source::Raw(b"act,prompt\n"),
+            Source::Raw(b"act,prompt\nfirst,p\n"),
+            Source::Raw(b"act,prompt\nsecond,p\n"),
+        ];
+        let prompts = PromptLoader::load_first_ok(&sources).await.unwrap();
+        assert_eq!(prompts.len(), 1);
+        assert_eq!(prompts[0].act, "first");
+        let missing = PromptLoader::load_first_ok(&sources[..2]).await;
+        assert!(matches!(missing, Err(Error::LoadError(_))));
+    }
+
+    #[test]
+    fn trim_fields() {
+        let csv = b"act,prompt\n\" Linux Terminal \",\" act as a terminal \"\n";
+        let trimmed = PromptLoader::parse(csv).pop().unwrap().unwrap();
+        assert_eq!(trimmed.act, "Linux Terminal");
+        assert_eq!(trimmed.prompt, "act as a terminal");
+        let untrimmed = PromptLoader::parse_untrimmed(csv).pop().unwrap().unwrap();
+        assert_eq!(untrimmed.act, " Linux Terminal ");
+    }
+
+    #[test]
+    fn categories() {
+        let parsed = PromptLoader::parse(b"act,prompt,category\na,p,code\nb,p,\n");
+        let prompts: Vec<_> = parsed.into_iter().map(Result::unwrap).collect();
+        assert_eq!(prompts[0].category(), "code");
+        assert_eq!(prompts[1].category, None);
+        assert_eq!(prompts[1].category(), UNCATEGORIZED);
+        let mut csv = vec![];
+        write_csv(&prompts, &mut csv).unwrap();
+        let parsed: Vec<_> = PromptLoader::parse(&csv).into_iter().collect();
+        assert_eq!(parsed, prompts.into_iter().map(Ok).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn write_csv_roundtrip() {
+        let prompts = vec![
+            Prompt {
+                act: "1".into(),
+                prompt: "multi\n\"line\"".into(),
+                category: None,
+            },
+            Prompt {
+                act: "1".into(),
+                prompt: "duplicate".into(),
+                category: None,
+            },
+        ];
+        let prompts = dedupe(prompts, DedupeStrategy::Act);
+        let mut csv = vec![];
+        write_csv(&prompts, &mut csv).unwrap();
+        let parsed: Vec<_> = PromptLoader::parse(&csv).into_iter().collect();
+        assert_eq!(parsed, prompts.into_iter().map(Ok).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn skip_empty_records() {
+        let example = "\n\"act\",\"prompt\"\n\"1\",\"1\"\n\"\",\"\"\n,\n\n";
+        let result = PromptLoader::load(&[Source::Raw(example.as_bytes())]).await;
+        assert_eq!(
+            result,
+            vec![Ok(Prompt {
+                act: "1".into(),
+                prompt: "1".into(),
+                category: None,
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn dropping_load_closes_connection() {
+        use std::time::Duration;
+        use tokio::{io::AsyncReadExt, net::TcpListener, time::timeout};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/prompts.csv", listener.local_addr().unwrap());
+        let sources = [Source::Http(&url)];
+        let load = PromptLoader::load(&sources);
+        // the server never responds so the load can only end by being dropped
+        assert!(timeout(Duration::from_millis(100), load).await.is_err());
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = vec![];
+        let closed = timeout(Duration::from_secs(5), socket.read_to_end(&mut request)).await;
+        assert!(matches!(closed, Ok(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn redirects() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // redirects /n to /n+1 and /loop to itself
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]);
+                let path = request.split(' ').nth(1).unwrap_or("/").to_owned();
+                let location = match path.trim_start_matches('/').parse::<usize>() {
+                    Ok(n) => format!("/{}", n + 1),
+                    Err(_) => path,
+                };
+                let response = format!(
+                    "HTTP/1.1 302 Found\r\nLocation: {location}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let options = FetchOptions::default();
+        let fetch = |path: &str| {
+            let url = format!("http://{addr}{path}");
+            let options = &options;
+            async move { PromptLoader::fetch(&url, &Validator::default(), options).await }
+        };
+        assert_eq!(
+            fetch("/0").await.unwrap_err(),
+            Error::LoadError("too many redirects".into())
+        );
+        assert_eq!(
+            fetch("/loop").await.unwrap_err(),
+            Error::LoadError(format!("redirect loop at http://{addr}/loop"))
+        );
+    }
+
+    #[test]
+    fn resolve_location() {
+        let current: Uri = "http://example.com/v1/prompts/all.csv?lang=en"
+            .parse()
+            .unwrap();
+        let resolve = |location: &'static str| {
+            PromptLoader::resolve_location(&current, &header::HeaderValue::from_static(location))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            resolve("https://other.org/p.csv"),
+            "https://other.org/p.csv"
+        );
+        assert_eq!(resolve("/p.csv"), "http://example.com/p.csv");
+        assert_eq!(
+            resolve("prompts.csv"),
+            "http://example.com/v1/prompts/prompts.csv"
+        );
+        assert_eq!(
+            resolve("../v2/prompts.csv"),
+            "http://example.com/v1/v2/prompts.csv"
+        );
+        assert_eq!(
+            resolve("../../../p.csv?x=1"),
+            "http://example.com/p.csv?x=1"
+        );
+        assert_eq!(resolve("./"), "http://example.com/v1/prompts/");
+        assert_eq!(
+            resolve("?lang=de"),
+            "http://example.com/v1/prompts/all.csv?lang=de"
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_response() {
+        let (url, _) =
+            serve_once("HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nact,prompt\na,b\n").await;
+        let err = PromptLoader::fetch(&url, &Validator::default(), &FetchOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err, Error::LoadError("truncated response".into()));
+    }
+
+    #[tokio::test]
+    async fn fetch_with_headers() {
+        let (url, _) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/csv\r\nContent-Length: 11\r\n\r\nact,prompt\n",
+        )
+        .await;
+        let (headers, body) = PromptLoader::fetch_with_headers(&url, &FetchOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(headers[header::CONTENT_TYPE], "text/csv");
+        assert_eq!(&body[..], b"act,prompt\n");
+    }
+
+    #[test]
+    fn is_html() {
+        let html = |content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            headers
+        };
+        let csv = b"act,prompt\n";
+        assert!(PromptLoader::is_html(
+            &html("text/html; charset=utf-8"),
+            csv
+        ));
+        assert!(!PromptLoader::is_html(&html("text/csv"), csv));
+        assert!(PromptLoader::is_html(
+            &HeaderMap::new(),
+            b"\n  <!DOCTYPE HTML><html></html>"
+        ));
+        assert!(!PromptLoader::is_html(&HeaderMap::new(), csv));
+    }
+
+    #[tokio::test]
+    async fn accept_json() {
+        let body = r#"[{"act":"a","prompt":"p"}]"#;
+        let (url, request) = serve_once(format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ))
+        .await;
+        let options = FetchOptions {
+            accept: Accept::Json,
+            ..Default::default()
+        };
+        let prompts = PromptLoader::load_with_options(&[Source::Http(&url)], None, &options).await;
+        assert_eq!(prompts, vec![Ok(prompt("a", "p"))]);
+        let request = request.await.unwrap().to_lowercase();
+        assert!(request.contains("accept: application/json"));
+    }
+
+    #[tokio::test]
+    async fn parse_declared_format() {
+        // csv declared as json is not sniffed as csv
+        let body = "act,prompt\na,p\n";
+        let (url, _) = serve_once(format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ))
+        .await;
+        let prompts =
+            PromptLoader::load_with_options(&[Source::Http(&url)], None, &FetchOptions::default())
+                .await;
+        assert_eq!(prompts.len(), 1);
+        assert!(prompts[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        // accepts the connection but never responds
+        let server = tokio::spawn(async move { listener.accept().await });
+        let options = FetchOptions {
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let err = PromptLoader::fetch(&url, &Validator::default(), &options)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            Error::LoadError(format!("{url}: timed out after 100ms"))
+        );
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn custom_source() {
+        struct Custom;
+
+        #[async_trait]
+        impl PromptSource for Custom {
+            async fn fetch(&self) -> Result<Vec<u8>> {
+                Ok(b"\"act\",\"prompt\"\n\"custom\",\"custom\"\n".to_vec())
+            }
+        }
+
+        let raw = Source::Raw(b"\"act\",\"prompt\"\n\"raw\",\"raw\"\n");
+        let sources: [&dyn PromptSource; 2] = [&Custom, &raw];
+        let result: Vec<_> = PromptLoader::load(&sources)
+            .await
+            .into_iter()
+            .map(|p| p.map(|p| p.act))
+            .collect();
+        assert_eq!(result, vec![Ok("custom".into()), Ok("raw".into())]);
+    }
+
+    #[tokio::test]
+    async fn load_max() {
+        let example = "\"act\",\"prompt\"\n\"1\",\"1\"\n\"2\",\"2\"\n\"3\",\"3\"\n";
+        let result = PromptLoader::load_max(&[Source::Raw(example.as_bytes())], Some(2)).await;
+        assert_eq!(result.len(), 2);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn load_blocking() {
+        let example = "\"act\",\"prompt\"\n\"1\",\"1\"\n";
+        let result = PromptLoader::load_blocking(&[Source::Raw(example.as_bytes())]).unwrap();
+        assert_eq!(result.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn format_error_contains_line() {
+        let example = "\"act\",\"prompt\"\n\"1\",\"1\"\n\"2\"\n";
+        let result = PromptLoader::load(&[Source::Raw(example.as_bytes())]).await;
+        assert_eq!(
+            result[1],
+            Err(Error::FormatError(
+                "line 3: expected 2 fields but found 1".into()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_with_bom() {
+        let example = "\u{feff}\"act\",\"prompt\"\n\"1\",\"1\"\n";
+        let result = PromptLoader::load(&[Source::Raw(example.as_bytes())]).await;
+        assert_eq!(
+            result,
+            vec![Ok(Prompt {
+                act: "1".into(),
+                prompt: "1".into(),
+                category: None,
+            })]
+        );
+    }
+
+    #[test]
+    fn from_arg() {
+        assert_eq!(
+            Source::from_arg("https://example.com/prompts.csv"),
+            Source::Http("https://example.com/prompts.csv")
+        );
+        assert_eq!(Source::from_arg("-"), Source::Stdin);
+        assert_eq!(Source::from_arg("prompts.csv"), Source::File("prompts.csv"));
+    }
+
+    #[test]
+    fn render() {
+        let p = Prompt {
+            act: "test".into(),
+            prompt: "I want you to act as a {{ role }} for {{name}}. {{unknown}}".into(),
+            category: None,
+        };
+        let vars = HashMap::from([
+            ("role".to_string(), "translator".to_string()),
+            ("name".to_string(), "me".to_string()),
+        ]);
+        assert_eq!(
+            p.render(&vars),
+            "I want you to act as a translator for me. {{unknown}}"
+        );
+        assert!(p.render_strict(&vars).is_err());
+    }
 }
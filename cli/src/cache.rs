@@ -12,19 +12,56 @@ use std::{
 };
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+use crate::json_file as json;
 use crate::toml_file as toml;
 use anyhow::{bail, Context, Result};
 
+/// The version of the cached values, it is used as subdirectory of the cache dir.
+///
+/// Must be increased when cached types change incompatibly, so that older entries are not read.
+pub const SCHEMA_VERSION: &str = "v1";
+
 /// Initializes the cache.
 ///
 /// Before returning the cache the given dir is verified and if it does not exist than it will be
 /// created.
-pub fn init<P>(dir: P, max_cache_age: Duration) -> Result<Cache<P>>
+pub fn init<P>(dir: P, max_cache_age: Duration, format: Format) -> Result<Cache<P>>
 where
     P: AsRef<Path>,
 {
-    Cache::init(dir, max_cache_age)
+    Cache::init(dir, max_cache_age, format)
+}
+
+/// The format the cached values are stored in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// The file extension matching the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Toml => "toml",
+            Format::Json => "json",
+        }
+    }
+}
+
+/// Creates a cache key for the given value.
+///
+/// The value is serialized to JSON and the hex encoded SHA-256 of it is returned. This way
+/// distinct inputs (e.g. queries with different sampling parameters) get distinct cache entries.
+pub fn key_of<T>(value: &T) -> Result<String>
+where
+    T: Serialize,
+{
+    let json = serde_json::to_vec(value).context("unable to serialize cache key")?;
+    Ok(format!("{:x}", Sha256::digest(json)))
 }
 
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -46,6 +83,16 @@ where
     value: T,
 }
 
+impl<T> Value<T>
+where
+    T: serde::Serialize,
+{
+    /// Creates a value with the given creation time, as duration since UNIX_EPOCH.
+    pub fn new(value: T, created: Duration) -> Self {
+        Self { created, value }
+    }
+}
+
 impl<T> From<T> for Value<T>
 where
     T: serde::Serialize,
@@ -55,15 +102,40 @@ where
         let created = start
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards");
-        Self { created, value }
+        Self::new(value, created)
     }
 }
 
+/// Only the creation time of a cached value, to skip parsing the value itself.
+///
+/// Unknown fields are not denied so that the `value` of any `Value<T>` is ignored.
+#[derive(Serialize, Deserialize)]
+struct Created {
+    created: Duration,
+}
+
+/// An entry of the cache dir.
+#[derive(Debug)]
+pub struct CacheEntry {
+    /// The file name including the extension.
+    pub file_name: String,
+    /// How old the value is.
+    pub age: Duration,
+    /// Is true when the age exceeds the maximum cache age.
+    pub expired: bool,
+}
+
 /// Handles the cache dir.
 pub struct Cache<P> {
     /// The directory to work in.
     dir: P,
     max_cache_age: Duration,
+    /// The format of the cache files, also defines the file extension.
+    format: Format,
+    /// Skips storing and deleting, cached values are still loaded.
+    read_only: bool,
+    /// Returns true for names, without extension, that `prune` must not delete.
+    keep: fn(&str) -> bool,
 }
 
 fn check_or_create_dir<P>(dir: P) -> Result<()>
@@ -79,7 +151,7 @@ where
         }
         Ok(())
     } else {
-        fs::create_dir(dir.as_ref()).with_context(|| {
+        fs::create_dir_all(dir.as_ref()).with_context(|| {
             format!(
                 "unable to create dir {}",
                 &dir.as_ref().to_str().unwrap_or_default()
@@ -93,57 +165,238 @@ where
     P: AsRef<Path>,
 {
     /// Verifies if given dir exists or creates it before returning a Cache
-    fn init(dir: P, max_cache_age: Duration) -> Result<Self> {
+    fn init(dir: P, max_cache_age: Duration, format: Format) -> Result<Self> {
         check_or_create_dir(&dir)?;
-        Ok(Self { dir, max_cache_age })
+        Ok(Self {
+            dir,
+            max_cache_age,
+            format,
+            read_only: false,
+            keep: |_| false,
+        })
+    }
+
+    /// Makes the cache read-only; stores, removals and pruning are skipped silently.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Excludes the names, without extension, matching `keep` from pruning.
+    ///
+    /// Is meant for values that are read via `load_stale` regardless of their age.
+    pub fn keep(mut self, keep: fn(&str) -> bool) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Returns the path of the cache file for the given name.
+    ///
+    /// The extension is added based on the format of the cache.
+    pub fn cache_path(&self, name: &str) -> PathBuf {
+        let mut path = PathBuf::from(self.dir.as_ref());
+        path.push(format!("{name}.{}", self.format.extension()));
+        path
+    }
+
+    async fn load<T>(&self, name: &str) -> Result<Value<T>>
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        let path = self.cache_path(name);
+        match self.format {
+            Format::Toml => toml::load(path).await,
+            Format::Json => json::load(path).await,
+        }
     }
 
     /// Loads a cached value from the cache directory.
     ///
-    /// The `file_name` parameter specifies the name of the file to load from the cache directory.
+    /// The `name` parameter specifies the name of the file, without extension, to load from the cache directory.
     /// The function returns `Ok(Some(T))` if the file exists in the cache directory and its age is less than the maximum cache age. Otherwise, it returns `Ok(None)`
-    pub async fn load_cached<T>(&self, file_name: &str) -> Result<Option<T>>
+    #[tracing::instrument(skip(self))]
+    pub async fn load_cached<T>(&self, name: &str) -> Result<Option<T>>
     where
         T: Serialize + serde::de::DeserializeOwned,
     {
-        let mut path = PathBuf::from(self.dir.as_ref());
-        path.push(file_name);
-        let cached: Value<T> = toml::load(path).await?;
+        let cached: Value<T> = self.load(name).await?;
         let created = cached.created;
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
-        if now - created < self.max_cache_age {
+        // a value created in the future, e.g. by a skewed clock, has no age
+        if now.saturating_sub(created) < self.max_cache_age {
+            tracing::debug!(name, "cache hit");
             Ok(Some(cached.value))
         } else {
+            tracing::debug!(name, "cache expired");
             Ok(None)
         }
     }
 
+    /// Loads a cached value from the cache directory regardless of its age.
+    ///
+    /// This is useful for values that can be revalidated, e.g. via an ETag.
+    pub async fn load_stale<T>(&self, name: &str) -> Result<T>
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        let cached: Value<T> = self.load(name).await?;
+        Ok(cached.value)
+    }
+
     /// Stores a value in the cache directory.
     ///
-    /// The `file_name` parameter specifies the name of the file to store in the cache directory.
+    /// The `name` parameter specifies the name of the file, without extension, to store in the cache directory.
     /// The `to_cache` parameter specifies the value to store in the cache directory.
-    pub async fn store_cache<T>(&self, file_name: &str, to_cache: T) -> Result<()>
+    #[tracing::instrument(skip(self, to_cache))]
+    pub async fn store_cache<T>(&self, name: &str, to_cache: T) -> Result<()>
     where
         T: serde::ser::Serialize,
     {
-        let mut path = PathBuf::from(self.dir.as_ref());
-        path.push(file_name);
-        toml::replace(path, to_cache).await
+        if self.read_only {
+            return Ok(());
+        }
+        let path = self.cache_path(name);
+        match self.format {
+            Format::Toml => toml::replace(path, to_cache).await,
+            Format::Json => json::replace(path, to_cache).await,
+        }
+    }
+
+    /// Removes the cached value of the given name.
+    pub async fn remove(&self, name: &str) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        Ok(tokio::fs::remove_file(self.cache_path(name)).await?)
+    }
+
+    /// Lists the entries of the cache format with their age.
+    ///
+    /// Only the creation time of each entry is read, files that cannot be read as cached value
+    /// are skipped.
+    pub async fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let mut dir = tokio::fs::read_dir(self.dir.as_ref()).await?;
+        let mut entries = vec![];
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(self.format.extension()) {
+                continue;
+            }
+            let created: Created = match match self.format {
+                Format::Toml => toml::load(&path).await,
+                Format::Json => json::load(&path).await,
+            } {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            let age = now.saturating_sub(created.created);
+            entries.push(CacheEntry {
+                file_name: entry.file_name().to_string_lossy().into_owned(),
+                age,
+                expired: age >= self.max_cache_age,
+            });
+        }
+        entries.sort_by_key(|e| e.age);
+        Ok(entries)
+    }
+
+    /// Deletes all entries of the cache format that are older than the maximum cache age.
+    ///
+    /// Files that cannot be read as cached value and names matching `keep` are kept. Returns the
+    /// amount of deleted entries.
+    #[tracing::instrument(skip(self))]
+    pub async fn prune(&self) -> Result<usize> {
+        if self.read_only {
+            return Ok(0);
+        }
+        let mut pruned = 0;
+        let extension = format!(".{}", self.format.extension());
+        for entry in self.entries().await?.into_iter().filter(|e| {
+            let name = e.file_name.strip_suffix(&extension).unwrap_or(&e.file_name);
+            e.expired && !(self.keep)(name)
+        }) {
+            let path = PathBuf::from(self.dir.as_ref()).join(&entry.file_name);
+            tokio::fs::remove_file(path).await?;
+            pruned += 1;
+        }
+        tracing::debug!(pruned, "pruned cache");
+        Ok(pruned)
+    }
+
+    /// Stores the value unless the cached entry was created after it.
+    ///
+    /// Prevents that e.g. a slower invocation overwrites the result of a later one. Returns true
+    /// when the value was stored.
+    #[tracing::instrument(skip(self, value))]
+    pub async fn store_cache_if_newer<T>(&self, name: &str, value: &Value<T>) -> Result<bool>
+    where
+        T: serde::ser::Serialize,
+    {
+        let existing: Option<Created> = match self.format {
+            Format::Toml => toml::load(self.cache_path(name)).await.ok(),
+            Format::Json => json::load(self.cache_path(name)).await.ok(),
+        };
+        if matches!(existing, Some(e) if e.created > value.created) {
+            tracing::debug!(name, "skipped, cached value is newer");
+            return Ok(false);
+        }
+        self.store_cache(name, value).await?;
+        Ok(true)
     }
 
-    /// Retrieves a value from the cache directory, or loads it if it does not exist.
+    /// Stores a value in the cache directory and blocks until it is written.
     ///
-    /// The `file_name` parameter specifies the name of the file to retrieve from the cache directory.
-    /// The `input` parameter specifies the input to the loader function.
-    /// The `loader` parameter is a closure that takes an input and returns a future that resolves to a result of type `T`.
+    /// Is meant for places that cannot await, like `Drop`.
+    pub fn store_cache_blocking<T>(&self, name: &str, to_cache: T) -> Result<()>
+    where
+        T: serde::ser::Serialize,
+    {
+        if self.read_only {
+            return Ok(());
+        }
+        let path = self.cache_path(name);
+        match self.format {
+            Format::Toml => toml::replace_blocking(path, to_cache),
+            Format::Json => json::replace_blocking(path, to_cache),
+        }
+    }
+
+    /// Returns the cached value of `file_name` or stores and returns the value of `loader`.
     ///
-    /// The function first attempts to load the value from the cache directory using the `load_cached` function.
-    /// If the value exists in the cache directory and its age is less than the maximum cache age, the function returns the cached value.
-    /// Otherwise, the function calls the `loader` closure with the `input` parameter to load the value.
-    /// The function then stores the loaded value in the cache directory using the `store_cache` function and returns the loaded value.
+    /// `file_name` is the name within the cache dir without the extension of the format, the same
+    /// name is used to load and to store the value. The loader is only called when there is no
+    /// valid cached value; a value that was stored concurrently after the loader started is not
+    /// overwritten, see `store_cache_if_newer`.
+    pub async fn get_or_insert_with<T, N, L, F>(&self, file_name: N, loader: L) -> Result<T>
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+        N: AsRef<Path>,
+        L: FnOnce() -> F,
+        F: Future<Output = Result<T>>,
+    {
+        let name = file_name
+            .as_ref()
+            .to_str()
+            .context("cache file name is not valid UTF-8")?;
+        match self.load_cached::<T>(name).await {
+            Ok(Some(x)) => Ok(x),
+            Ok(None) | Err(_) => {
+                tracing::debug!(name, "cache miss");
+                let started = SystemTime::now().duration_since(UNIX_EPOCH)?;
+                let r = loader().await?;
+                let cached = Value::new(r, started);
+                self.store_cache_if_newer(name, &cached).await?;
+                Ok(cached.value)
+            }
+        }
+    }
+
+    /// Retrieves a value from the cache directory, or loads it via `loader(input)`.
+    #[deprecated(note = "use `get_or_insert_with`")]
     pub async fn with_cached<F, T, I>(
         &self,
-        file_name: &str,
+        name: &str,
         input: I,
         mut loader: impl FnMut(I) -> F,
     ) -> Result<T>
@@ -151,14 +404,146 @@ where
         T: Serialize + serde::de::DeserializeOwned + Sized,
         F: Future<Output = Result<T>>,
     {
-        match self.load_cached::<T>("prompts.toml").await {
-            Ok(Some(x)) => Ok(x),
-            Ok(None) | Err(_) => {
-                let r = loader(input).await?;
-                let cached: Value<T> = r.into();
-                self.store_cache(file_name, &cached).await?;
-                Ok(cached.value)
-            }
+        self.get_or_insert_with(name, || loader(input)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yaoaic::Message;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn messages_keep_order() {
+        for format in [Format::Toml, Format::Json] {
+            messages_keep_order_in(format).await;
         }
     }
+
+    #[tokio::test]
+    async fn expired_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = init(dir.path(), Duration::from_secs(60), Format::Toml).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let expired = Value::new("old", now - Duration::from_secs(61));
+        cache.store_cache("expired", expired).await.unwrap();
+        let loaded: Option<String> = cache.load_cached("expired").await.unwrap();
+        assert_eq!(loaded, None);
+        let stale: String = cache.load_stale("expired").await.unwrap();
+        assert_eq!(stale, "old");
+    }
+
+    #[tokio::test]
+    async fn value_from_the_future() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = init(dir.path(), Duration::from_secs(60), Format::Toml).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let future = Value::new("future", now + Duration::from_secs(3600));
+        cache.store_cache("future", future).await.unwrap();
+        let loaded: Option<String> = cache.load_cached("future").await.unwrap();
+        assert_eq!(loaded.as_deref(), Some("future"));
+    }
+
+    #[tokio::test]
+    async fn get_or_insert_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = init(dir.path(), Duration::from_secs(60), Format::Toml).unwrap();
+        let loaded: String = cache
+            .get_or_insert_with("key", || async { Ok("loaded".to_owned()) })
+            .await
+            .unwrap();
+        assert_eq!(loaded, "loaded");
+        assert!(cache.cache_path("key").exists());
+        let cached: String = cache
+            .get_or_insert_with("key", || async { bail!("loader must not be called") })
+            .await
+            .unwrap();
+        assert_eq!(cached, "loaded");
+    }
+
+    #[tokio::test]
+    async fn read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = init(dir.path(), Duration::from_secs(60), Format::Toml).unwrap();
+        let value: Value<&str> = "cached".into();
+        cache.store_cache("a", value).await.unwrap();
+        let cache = cache.read_only(true);
+        let value: Value<&str> = "new".into();
+        cache.store_cache("a", value).await.unwrap();
+        let value: Value<&str> = "new".into();
+        cache.store_cache("b", value).await.unwrap();
+        let loaded: Option<String> = cache.load_cached("a").await.unwrap();
+        assert_eq!(loaded.as_deref(), Some("cached"));
+        assert!(!cache.cache_path("b").exists());
+    }
+
+    #[tokio::test]
+    async fn store_cache_if_newer() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = init(dir.path(), Duration::from_secs(60), Format::Toml).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let older = Value::new("older", now - Duration::from_secs(1));
+        let newer = Value::new("newer", now);
+        assert!(cache.store_cache_if_newer("v", &newer).await.unwrap());
+        assert!(!cache.store_cache_if_newer("v", &older).await.unwrap());
+        let loaded: Option<String> = cache.load_cached("v").await.unwrap();
+        assert_eq!(loaded.as_deref(), Some("newer"));
+    }
+
+    #[tokio::test]
+    async fn prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = init(dir.path(), Duration::from_secs(60), Format::Json).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let expired = Value::new("old", now - Duration::from_secs(61));
+        cache.store_cache("expired", expired).await.unwrap();
+        cache
+            .store_cache("fresh", Value::from("new"))
+            .await
+            .unwrap();
+        assert_eq!(cache.prune().await.unwrap(), 1);
+        assert!(!cache.cache_path("expired").exists());
+        assert!(cache.cache_path("fresh").exists());
+    }
+
+    #[tokio::test]
+    async fn prune_keeps_stale_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = init(dir.path(), Duration::from_secs(60), Format::Toml)
+            .unwrap()
+            .keep(crate::read_regardless_of_age);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let old = || Value::new(vec!["old".to_owned()], now - Duration::from_secs(61));
+        let remote = format!("key{}", crate::REMOTE_SUFFIX);
+        for name in [crate::conversation::LAST_MESSAGES, &remote, "expired"] {
+            cache.store_cache(name, old()).await.unwrap();
+        }
+        assert_eq!(cache.prune().await.unwrap(), 1);
+        assert!(!cache.cache_path("expired").exists());
+        let last: Vec<String> = cache
+            .load_stale(crate::conversation::LAST_MESSAGES)
+            .await
+            .unwrap();
+        assert_eq!(last, vec!["old"]);
+        assert!(cache.cache_path(&remote).exists());
+    }
+
+    async fn messages_keep_order_in(format: Format) {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = init(dir.path(), Duration::from_secs(60), format).unwrap();
+        let messages: Vec<Message> = ["system", "user", "assistant", "user", "assistant"]
+            .iter()
+            .enumerate()
+            .map(|(i, role)| Message {
+                role: role.to_string(),
+                content: format!("{i}: turn of {role}\nwith a second line"),
+            })
+            .collect();
+        let cached: Value<Vec<Message>> = messages.clone().into();
+        cache.store_cache("messages", cached).await.unwrap();
+        assert!(cache.cache_path("messages").exists());
+        let loaded: Option<Vec<Message>> = cache.load_cached("messages").await.unwrap();
+        assert_eq!(loaded, Some(messages));
+    }
 }
@@ -0,0 +1,45 @@
+//! Handles the optional configuration file `~/.config/yaoaic/config.toml`.
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::toml_file as toml;
+
+/// The configuration of the cli.
+///
+/// Command line arguments take precedence over the configuration.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// The urls of the remote prompts, replaces the default awesome-chatgpt-prompts url.
+    pub prompts_urls: Vec<String>,
+    /// Aliases for model ids, e.g. `big = "gpt-4o"` within `[models]`.
+    pub models: HashMap<String, String>,
+    /// The sampling parameters used when not given as argument, within `[defaults]`.
+    pub defaults: Defaults,
+}
+
+/// Default sampling parameters, unset ones fall back to the compiled defaults.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Defaults {
+    /// The model or an alias of `[models]`.
+    pub model: Option<String>,
+    pub top_p: Option<f32>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<usize>,
+}
+
+/// Loads the configuration from path.
+///
+/// When the file does not exist the default configuration is returned.
+pub async fn load<P>(path: P) -> Result<Config>
+where
+    P: AsRef<Path>,
+{
+    if !path.as_ref().exists() {
+        return Ok(Config::default());
+    }
+    toml::load(path).await
+}
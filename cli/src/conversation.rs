@@ -0,0 +1,210 @@
+//! Persists the conversation as it grows.
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use yaoaic::Message;
+
+use crate::cache::{Cache, Value};
+
+/// The cache name of the last conversation.
+pub const LAST_MESSAGES: &str = "last_messages";
+
+/// The prefix of conversations kept in the history, followed by the start time in milliseconds.
+const HISTORY_PREFIX: &str = "messages-";
+
+/// Holds the messages of the current conversation.
+///
+/// Changes are stored via `flush`; when the guard is dropped with unstored changes, e.g. because
+/// of an early return, they are stored blocking so that the conversation is not lost.
+pub struct Conversation<'a, P>
+where
+    P: AsRef<Path>,
+{
+    cache: Option<&'a Cache<P>>,
+    messages: Vec<Message>,
+    dirty: bool,
+    max_messages: Option<usize>,
+    /// The history cache and the amount of conversations to keep within it.
+    history: Option<(&'a Cache<P>, usize)>,
+    /// The name within the history, based on the start of the conversation.
+    name: String,
+}
+
+/// Returns the names of the conversations within the history, the most recent first.
+pub async fn history<P>(cache: &Cache<P>) -> Result<Vec<String>>
+where
+    P: AsRef<Path>,
+{
+    let mut started: Vec<(u128, String)> = cache
+        .entries()
+        .await?
+        .into_iter()
+        .filter_map(|e| {
+            let name = e.file_name.rsplit_once('.')?.0;
+            let started = name.strip_prefix(HISTORY_PREFIX)?.parse().ok()?;
+            Some((started, name.to_owned()))
+        })
+        .collect();
+    started.sort_by_key(|(started, _)| std::cmp::Reverse(*started));
+    Ok(started.into_iter().map(|(_, name)| name).collect())
+}
+
+/// Removes all but the `keep` most recent conversations of the history.
+async fn rotate<P>(cache: &Cache<P>, keep: usize) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    for name in history(cache).await?.into_iter().skip(keep) {
+        cache.remove(&name).await?;
+    }
+    Ok(())
+}
+
+/// Returns the most recent `max` messages, the leading system messages are always kept.
+///
+/// The kept turns start with a user message so that no answer is stored without its question.
+pub fn trim(messages: &[Message], max: usize) -> Vec<Message> {
+    let system = messages.iter().take_while(|m| m.role == "system").count();
+    let turns = &messages[system..];
+    let mut start = turns.len().saturating_sub(max);
+    while start < turns.len() && turns[start].role == "assistant" {
+        start += 1;
+    }
+    messages[..system]
+        .iter()
+        .chain(&turns[start..])
+        .cloned()
+        .collect()
+}
+
+impl<'a, P> Conversation<'a, P>
+where
+    P: AsRef<Path>,
+{
+    /// Starts a conversation with the given messages; without a cache nothing is stored.
+    pub fn new(cache: Option<&'a Cache<P>>, messages: Vec<Message>) -> Self {
+        Self {
+            cache,
+            messages,
+            dirty: true,
+            max_messages: None,
+            history: None,
+            name: format!(
+                "{HISTORY_PREFIX}{}",
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis()
+            ),
+        }
+    }
+
+    /// Additionally keeps the conversation within the history cache when both are given.
+    ///
+    /// Only the `keep` most recent conversations are kept, older ones are removed on `flush`.
+    /// `last_messages` stays the pointer to the latest conversation.
+    pub fn history(mut self, cache: Option<&'a Cache<P>>, keep: Option<usize>) -> Self {
+        self.history = cache.zip(keep);
+        self
+    }
+
+    /// Limits the stored messages to the most recent `max`, see `trim`.
+    pub fn max_messages(mut self, max: Option<usize>) -> Self {
+        self.max_messages = max;
+        self
+    }
+
+    fn to_store(&self) -> Vec<Message> {
+        match self.max_messages {
+            Some(max) => trim(&self.messages, max),
+            None => self.messages.clone(),
+        }
+    }
+
+    /// Adds messages, e.g. the answer of a turn.
+    pub fn extend(&mut self, messages: impl IntoIterator<Item = Message>) {
+        self.messages.extend(messages);
+        self.dirty = true;
+    }
+
+    /// Stores the conversation as `last_messages` and within the history.
+    pub async fn flush(&mut self) -> Result<()> {
+        if let Some(c) = self.cache {
+            let cached: Value<Vec<Message>> = self.to_store().into();
+            c.store_cache(LAST_MESSAGES, cached).await?;
+        }
+        if let Some((c, keep)) = self.history {
+            let cached: Value<Vec<Message>> = self.to_store().into();
+            c.store_cache(&self.name, cached).await?;
+            rotate(c, keep).await?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl<'a, P> Drop for Conversation<'a, P>
+where
+    P: AsRef<Path>,
+{
+    fn drop(&mut self) {
+        if let (true, Some(c)) = (self.dirty, self.cache) {
+            let cached: Value<Vec<Message>> = self.to_store().into();
+            if let Err(e) = c.store_cache_blocking(LAST_MESSAGES, cached) {
+                eprintln!("warning: unable to store conversation: {e}");
+            }
+        }
+        // the history is rotated on the next flush
+        if let (true, Some((c, _))) = (self.dirty, self.history) {
+            let cached: Value<Vec<Message>> = self.to_store().into();
+            if let Err(e) = c.store_cache_blocking(&self.name, cached) {
+                eprintln!("warning: unable to store conversation in history: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_keeps_system_and_whole_turns() {
+        let messages: Vec<Message> = ["system", "user", "assistant", "user", "assistant"]
+            .iter()
+            .enumerate()
+            .map(|(i, role)| Message {
+                role: role.to_string(),
+                content: i.to_string(),
+            })
+            .collect();
+        let contents = |m: Vec<Message>| m.into_iter().map(|m| m.content).collect::<Vec<_>>();
+        assert_eq!(contents(trim(&messages, 2)), vec!["0", "3", "4"]);
+        assert_eq!(contents(trim(&messages, 3)), vec!["0", "3", "4"]);
+        assert_eq!(contents(trim(&messages, 10)), vec!["0", "1", "2", "3", "4"]);
+    }
+
+    #[tokio::test]
+    async fn history_is_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = crate::cache::init(
+            dir.path(),
+            std::time::Duration::from_secs(60),
+            Default::default(),
+        )
+        .unwrap();
+        for started in [3, 1, 2] {
+            let cached: Value<Vec<Message>> = vec![].into();
+            let name = format!("{HISTORY_PREFIX}{started}");
+            cache.store_cache(&name, cached).await.unwrap();
+        }
+        rotate(&cache, 2).await.unwrap();
+        assert_eq!(
+            history(&cache).await.unwrap(),
+            vec!["messages-3", "messages-2"]
+        );
+    }
+}
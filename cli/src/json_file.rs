@@ -0,0 +1,57 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use tokio::io::AsyncReadExt;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+pub async fn replace<P, T>(path: P, to_cache: T) -> Result<()>
+where
+    P: AsRef<Path>,
+    T: serde::ser::Serialize,
+{
+    let cached_json =
+        serde_json::to_string_pretty(&to_cache).context("unable to write cached json")?;
+    let mut file = File::create(path).await?;
+    file.write_all(cached_json.as_bytes()).await?;
+    tracing::debug!(bytes = cached_json.len(), "stored");
+    Ok(())
+}
+
+/// Like `replace` but blocks, e.g. to store within `Drop`.
+pub fn replace_blocking<P, T>(path: P, to_cache: T) -> Result<()>
+where
+    P: AsRef<Path>,
+    T: serde::ser::Serialize,
+{
+    let cached = serde_json::to_string_pretty(&to_cache).context("unable to write cached json")?;
+    std::fs::write(path, &cached)?;
+    tracing::debug!(bytes = cached.len(), "stored");
+    Ok(())
+}
+
+pub async fn load<P, T>(path: P) -> Result<T>
+where
+    T: Serialize + serde::de::DeserializeOwned,
+    P: AsRef<Path>,
+{
+    let mut f = File::open(path.as_ref()).await.with_context(|| {
+        format!(
+            "{} unable to open.",
+            path.as_ref().to_str().unwrap_or_default()
+        )
+    })?;
+    let mut cached = String::new();
+    f.read_to_string(&mut cached)
+        .await
+        .context("unable to load into string")?;
+    tracing::debug!(bytes = cached.len(), "loaded");
+    let cached: T = serde_json::from_str(&cached).with_context(|| {
+        format!(
+            "{} has unknown format.",
+            path.as_ref().to_str().unwrap_or_default()
+        )
+    })?;
+    Ok(cached)
+}
@@ -1,81 +1,267 @@
-use std::{fs, time::Duration};
+use std::{collections::HashMap, fs, io::Write, path::Path, time::Duration};
 
 use clap::{Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
 
 use yaoaic::{Message, OpenAIClient, Query};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 
 mod cache;
+mod config;
+mod conversation;
+mod json_file;
 mod toml_file;
-#[derive(Default, Clone, ValueEnum)]
-enum Model {
-    /// The default model.
-    #[default]
-    GPT35Turbo,
-    /// The code-davinci model.
-    CodeDavinci,
+mod transcript;
+
+/// Resolves a model argument.
+///
+/// Aliases of the config are resolved first, everything else is parsed as model name or id.
+fn resolve_model(arg: &str, aliases: &HashMap<String, String>) -> Result<yaoaic::Model> {
+    let id = aliases.get(arg).map(String::as_str).unwrap_or(arg);
+    Ok(id.parse()?)
 }
 
-impl Model {
-    fn as_yaoic_model(&self) -> yaoaic::Model {
-        match self {
-            Model::GPT35Turbo => yaoaic::Model::GPT35Turbo,
-            Model::CodeDavinci => yaoaic::Model::CodeDavinci,
+/// The suffix of the cache names of remote prompts.
+const REMOTE_SUFFIX: &str = "_remote";
+
+/// Returns true for cache names that are read regardless of their age and so must not be pruned.
+///
+/// The last conversation is continued and remote prompts are revalidated or used as fallback.
+fn read_regardless_of_age(name: &str) -> bool {
+    name == conversation::LAST_MESSAGES || name.ends_with(REMOTE_SUFFIX)
+}
+
+/// Prompts of a http source together with the validator of the response.
+#[derive(Serialize, Deserialize)]
+struct RemotePrompts {
+    validator: prompts::Validator,
+    prompts: Vec<prompts::Prompt>,
+}
+
+/// Loads the prompts of a http source, reusing the cached prompts when the source is unchanged.
+///
+/// When the source cannot be loaded the last cached prompts are used regardless of their age.
+async fn remote_prompts<P>(
+    c: &cache::Cache<P>,
+    url: &str,
+    max_prompts: Option<usize>,
+    options: &prompts::FetchOptions,
+) -> Result<Vec<prompts::Result<prompts::Prompt>>>
+where
+    P: AsRef<Path>,
+{
+    let file_name = format!("{}{REMOTE_SUFFIX}", cache::key_of(&(url, max_prompts))?);
+    let cached: Option<RemotePrompts> = c.load_stale(&file_name).await.ok();
+    let validator = cached
+        .as_ref()
+        .map(|c| c.validator.clone())
+        .unwrap_or_default();
+    match prompts::PromptLoader::load_if_modified(url, &validator, max_prompts, options).await {
+        Ok(prompts::Conditional::NotModified) => {
+            let Some(cached) = cached else {
+                return Ok(vec![]);
+            };
+            let prompts = cached.prompts.clone().into_iter().map(Ok).collect();
+            // refreshes the age, the prompts are known to be current
+            let refreshed: cache::Value<RemotePrompts> = cached.into();
+            c.store_cache(&file_name, refreshed).await?;
+            Ok(prompts)
+        }
+        Ok(prompts::Conditional::Modified { prompts, validator }) => {
+            let remote = RemotePrompts {
+                validator,
+                prompts: prompts.iter().filter_map(|p| p.clone().ok()).collect(),
+            };
+            let cached: cache::Value<RemotePrompts> = remote.into();
+            c.store_cache(&file_name, cached).await?;
+            Ok(prompts)
         }
+        Err(e) => match cached {
+            Some(cached) => {
+                eprintln!("warning: {e}; falling back to cached prompts of {url}");
+                Ok(cached.prompts.into_iter().map(Ok).collect())
+            }
+            None => Ok(vec![Err(e)]),
+        },
     }
 }
 
-pub async fn valid_prompts<'a>(sources: &[prompts::Source<'a>]) -> Result<Vec<prompts::Prompt>> {
-    let results = prompts::PromptLoader::load(sources).await;
-    let mut only_ok = Vec::with_capacity(results.len());
-    for r in results {
-        match r {
-            Ok(r) => only_ok.push(r),
-            Err(e) => eprintln!("warning: {e}"),
+pub async fn valid_prompts<P>(
+    c: Option<&cache::Cache<P>>,
+    sources: &[prompts::Source<'_>],
+    max_prompts: Option<usize>,
+    options: &prompts::FetchOptions,
+) -> Result<Vec<prompts::Prompt>>
+where
+    P: AsRef<Path>,
+{
+    let mut results = vec![];
+    for s in sources {
+        match (c, s) {
+            (Some(c), prompts::Source::Http(url)) => {
+                results.extend(remote_prompts(c, url, max_prompts, options).await?)
+            }
+            (None, prompts::Source::Http(url)) => {
+                let validator = prompts::Validator::default();
+                match prompts::PromptLoader::load_if_modified(url, &validator, max_prompts, options)
+                    .await
+                {
+                    Ok(prompts::Conditional::Modified { prompts, .. }) => results.extend(prompts),
+                    Ok(prompts::Conditional::NotModified) => {}
+                    Err(e) => results.push(Err(e)),
+                }
+            }
+            _ => results.extend(
+                prompts::PromptLoader::load_max(std::slice::from_ref(s), max_prompts).await,
+            ),
         }
     }
+    let (only_ok, errors) = prompts::partition_prompts(results);
+    for e in errors {
+        eprintln!("warning: {e}");
+    }
     Ok(only_ok)
 }
 
-pub async fn ask<'a>(query_client: (&'a Query, &'a OpenAIClient<'a>)) -> Result<Vec<Message>> {
-    let (q, client) = query_client;
-    let mut messages = q.messages.clone();
+/// Prints the streamed answer as it arrives and appends it to `content`.
+///
+/// `content` keeps what was received when the stream fails or the future is dropped.
+async fn stream(client: &OpenAIClient, q: &Query, content: &mut String) -> Result<()> {
+    let mut chunks = std::pin::pin!(client.send_query_stream(q).await?);
+    let mut stdout = std::io::stdout();
+    while let Some(chunk) = chunks.next().await {
+        if let Some(delta) = chunk?.content() {
+            write!(stdout, "{delta}")?;
+            stdout.flush()?;
+            content.push_str(delta);
+        }
+    }
+    writeln!(stdout)?;
+    Ok(())
+}
 
-    let response = client.send_query(&q).await?;
-    messages.extend(response.choices.into_iter().map(|c| c.message));
-    Ok(messages)
+pub async fn ask<'a>(query_client: (&'a Query, &'a OpenAIClient)) -> Result<Vec<Message>> {
+    let (q, client) = query_client;
+    let response = client.send_to_model(q).await?;
+    let mut q = q.clone();
+    q.push_response(&response);
+    Ok(q.messages)
 }
 
+const DEFAULT_PROMPTS_URL: &str =
+    "https://raw.githubusercontent.com/f/awesome-chatgpt-prompts/main/prompts.csv";
+
 //#[derive(Default, Serialize, Clone, Deserialize, ValueEnum)]
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_precedence_over_arg = true)]
 struct Cli {
-    #[arg(short, long, value_enum)]
-    model: Option<Model>,
-    #[arg(long, default_value_t = 0.5)]
-    top_p: f32,
+    /// The model: gpt35-turbo, code-davinci, an alias of the config or a model id; defaults to
+    /// $YAOAIC_MODEL
     #[arg(short, long)]
+    model: Option<String>,
+    /// The top-p value (default 0.5)
+    #[arg(long)]
+    top_p: Option<f32>,
+    #[arg(long)]
     max_tokens: Option<usize>,
+    /// Replaces --max-tokens for newer models that reject it
+    #[arg(long, conflicts_with = "max_tokens")]
+    max_completion_tokens: Option<usize>,
+    #[arg(short, long)]
+    temperature: Option<f32>,
 
     #[arg(long, default_value_t = true)]
     /// Enable or disable cache
     cache: bool,
+    /// Uses cached values but does not store new ones
+    #[arg(long)]
+    no_cache_write: bool,
     /// Sets the amount of seconds that a cache is valid (default 86400s or 24h.)
     #[arg(long, default_value_t = 60 * 60 * 24 * 1)]
     cache_timeout_second: u64,
+    /// The format of the cache files
+    #[arg(long, value_enum, default_value_t)]
+    cache_format: cache::Format,
+    /// Limits the stored conversation to the most recent messages, system messages are kept
+    #[arg(long)]
+    max_cached_messages: Option<usize>,
+    /// Keeps the last n conversations, see the history subcommand
+    #[arg(long, value_name = "N")]
+    history: Option<usize>,
 
     #[arg(short, long)]
     prompt: Option<String>,
+    /// Limits the amount of prompts loaded per source
+    #[arg(long)]
+    max_prompts: Option<usize>,
+    /// Sets a system message in front of the query
+    #[arg(long)]
+    system: Option<String>,
+    /// Sets the content of the file as system message in front of the query
+    #[arg(long)]
+    prompt_file: Option<String>,
+    /// Replaces the default url of the remote prompts; can be given multiple times
+    #[arg(long = "prompts-url")]
+    prompts_urls: Vec<String>,
+    /// Additional prompt sources; either a http(s) url, a file or `-` for stdin
+    #[arg(long = "source")]
+    sources: Vec<String>,
+    /// Adds prompts given as csv, e.g. `'"act","prompt"\n"Test","Say hi"'`; `\n` is a newline
+    #[arg(long)]
+    raw_prompts: Option<String>,
 
     #[arg(short, long, action = clap::ArgAction::SetTrue)]
     stdin: bool,
-    /// when no stdin is given, fallback to the file
-    input_file: Option<String>,
+    /// Sets a `key=value` pair used to fill `{{key}}` placeholders of a selected prompt
+    #[arg(long = "var", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+    /// Continues the last conversation, requires the cache
+    #[arg(long = "continue", action = clap::ArgAction::SetTrue)]
+    continue_conversation: bool,
+    /// Which messages of the last conversation are sent again when continuing
+    #[arg(long, value_enum, default_value_t, requires = "continue_conversation")]
+    resume_mode: ResumeMode,
+    /// Prints the whole conversation with colored role labels
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pretty: bool,
+    /// Prints each answer by the template, e.g. `{role}: {content}`; supports `{content}`,
+    /// `{role}`, `{index}`, `{model}` and `{total_tokens}`
+    #[arg(long, conflicts_with = "pretty")]
+    template: Option<String>,
+    /// Prints the answer while it is generated
+    #[arg(long, conflicts_with_all = ["pretty", "template"])]
+    stream: bool,
+    /// Prints the input followed by a `---` line before the answer
+    #[arg(long, conflicts_with = "pretty")]
+    echo_input: bool,
+    /// Prints debug information to stderr; can be refined with RUST_LOG
+    #[arg(short, long, action = clap::ArgAction::SetTrue)]
+    verbose: bool,
+    /// Prints errors as json object with `error_kind`, `message` and `code` to stderr
+    #[arg(long)]
+    json_errors: bool,
+    /// Overrides the timeout of API requests and of fetching prompt sources
+    #[arg(long)]
+    timeout_seconds: Option<u64>,
+    /// Appends stdin to the input files instead of ignoring them
+    #[arg(long, action = clap::ArgAction::SetTrue, requires = "stdin")]
+    append_stdin: bool,
+    /// when no stdin is given, fallback to the files; multiple files are concatenated
+    input_files: Vec<String>,
     #[command(subcommand)]
     cmd: Option<AdditionalCmd>,
 }
 
+fn parse_var(s: &str) -> Result<(String, String)> {
+    let (k, v) = s
+        .split_once('=')
+        .with_context(|| format!("invalid key=value: no `=` found in `{s}`"))?;
+    Ok((k.to_owned(), v.to_owned()))
+}
+
 #[derive(Subcommand)]
 enum AdditionalCmd {
     /// Adds files to myapp
@@ -83,129 +269,783 @@ enum AdditionalCmd {
         #[command(subcommand)]
         cmd: PromptCommands,
     },
+    /// Lists the models available for the api key
+    Models,
+    /// Verifies the api key and the connection to the API
+    Ping,
+    /// Shows previous conversations kept by --history
+    History {
+        #[command(subcommand)]
+        cmd: HistoryCommands,
+    },
+    /// Inspects the cache
+    Cache {
+        #[command(subcommand)]
+        cmd: CacheCommands,
+    },
+    /// Prints the estimated token count of the input without calling the API
+    Tokens {
+        /// the model to estimate for, overrides the global model
+        #[arg(short, long)]
+        model: Option<String>,
+    },
+    /// Sends each line of a file as an independent query after the --prompt-file and --system messages
+    Batch {
+        /// the file containing one query per line
+        file: String,
+        /// the maximum amount of queries in flight
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+        /// how the results are printed
+        #[arg(long, value_enum, default_value_t)]
+        output: BatchOutput,
+    },
+}
+
+#[derive(Default, Clone, Copy, ValueEnum)]
+enum BatchOutput {
+    /// `line: content` in the order of the file, errors are printed to stderr.
+    #[default]
+    Text,
+    /// One JSON object per result as soon as it arrives.
+    Ndjson,
+}
+
+/// A result of a batch run in the ndjson output.
+#[derive(Serialize)]
+struct BatchLine<'a> {
+    /// The line of the query within the batch file.
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<&'a yaoaic::Usage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum HistoryCommands {
+    /// Lists the kept conversations, the most recent first
+    List,
+    /// Prints the n-th most recent conversation
+    Show { n: usize },
+}
+
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Lists the cached entries, the most recent first
+    List,
 }
 
 #[derive(Subcommand)]
 enum PromptCommands {
     List {
         filter: Option<String>,
+        /// Orders the prompts by their relevance for the filter instead of substring matching
+        #[arg(long, requires = "filter")]
+        rank: bool,
+        /// Only lists prompts of the category, prompts without one are "uncategorized"
+        #[arg(long)]
+        category: Option<String>,
+        /// Orders the listed prompts, the shown index stays the one to select a prompt by
+        #[arg(long, conflicts_with = "rank")]
+        sort: Option<SortOrder>,
     },
     Select {
         /// when no stdin is given, fallback to the file
         option: String,
     },
+    /// Opens the prompt in $EDITOR and uses the edited text
+    Edit {
+        /// the index or act of the prompt
+        option: String,
+    },
+    /// Prints all prompts, without the duplicates defined by --dedupe, to stdout
+    Export {
+        #[arg(long, value_enum, default_value_t)]
+        format: ExportFormat,
+        /// when prompts are considered duplicates
+        #[arg(long, value_enum, default_value_t)]
+        dedupe: Dedupe,
+    },
+    /// Shows which acts of the local prompt sources differ from the remote ones
+    Diff,
+    /// Picks a random prompt and shows it
+    Random {
+        /// restricts the pool to prompts containing the filter
+        filter: Option<String>,
+        /// sends the picked prompt to the API instead of showing it
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        run: bool,
+        /// seed to make the pick reproducible
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+}
+
+#[derive(Default, Clone, Copy, ValueEnum)]
+enum ResumeMode {
+    /// The whole conversation.
+    #[default]
+    Full,
+    /// Only system and user messages, e.g. to regenerate the answers.
+    UserOnly,
+}
+
+impl ResumeMode {
+    fn keeps(&self, m: &Message) -> bool {
+        match self {
+            ResumeMode::Full => true,
+            ResumeMode::UserOnly => m.role != "assistant",
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, ValueEnum)]
+enum Dedupe {
+    /// Prompts with the same act.
+    #[default]
+    Act,
+    /// Prompts with the same prompt text.
+    Content,
+    /// Prompts with the same act or prompt text.
+    Both,
+}
+
+impl Dedupe {
+    fn as_strategy(&self) -> prompts::DedupeStrategy {
+        match self {
+            Dedupe::Act => prompts::DedupeStrategy::Act,
+            Dedupe::Content => prompts::DedupeStrategy::Content,
+            Dedupe::Both => prompts::DedupeStrategy::ActOrContent,
+        }
+    }
+}
+
+#[derive(Clone, ValueEnum)]
+enum SortOrder {
+    /// Alphabetically by act, ignoring case.
+    Act,
+    /// By the index of the prompt.
+    Index,
+    /// By the length of the prompt text.
+    Length,
+}
+
+#[derive(Default, Clone, ValueEnum)]
+enum ExportFormat {
+    /// A JSON array of prompts.
+    #[default]
+    Json,
+    /// The awesome-chatgpt-prompts csv format.
+    Csv,
+}
+
+/// Returns true when no category is given or the prompt belongs to it, ignoring case.
+fn in_category(prompt: &prompts::Prompt, category: Option<&str>) -> bool {
+    category.is_none_or(|c| prompt.category().eq_ignore_ascii_case(c))
+}
+
+/// Opens the text in $EDITOR and returns the edited text.
+///
+/// Fails when the editor exits with an error or the edited text is empty.
+fn edit(text: &str) -> Result<String> {
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_owned());
+    let file = tempfile::Builder::new()
+        .prefix("yaoaic")
+        .suffix(".md")
+        .tempfile()
+        .context("unable to create temporary file")?;
+    fs::write(file.path(), text).context("unable to write temporary file")?;
+    let status = std::process::Command::new(&editor)
+        .arg(file.path())
+        .status()
+        .with_context(|| format!("unable to start {editor}"))?;
+    if !status.success() {
+        bail!("{editor} exited with {status}");
+    }
+    let edited = fs::read_to_string(file.path()).context("unable to read temporary file")?;
+    if edited.trim().is_empty() {
+        bail!("edited prompt is empty");
+    }
+    Ok(edited)
+}
+
+/// A machine readable error printed with `--json-errors`.
+#[derive(Serialize)]
+struct JsonError {
+    /// One of `api`, `network` or `usage`.
+    error_kind: &'static str,
+    message: String,
+    code: Option<String>,
+}
+
+impl From<&anyhow::Error> for JsonError {
+    fn from(e: &anyhow::Error) -> Self {
+        let (error_kind, code) = e
+            .chain()
+            .find_map(|c| {
+                if let Some(e) = c.downcast_ref::<yaoaic::Error>() {
+                    Some(match e {
+                        yaoaic::Error::Api(a) => ("api", a.code().map(str::to_owned)),
+                        yaoaic::Error::Unauthorized => ("api", Some("unauthorized".to_owned())),
+                        yaoaic::Error::Unknown(_) => ("network", None),
+                        yaoaic::Error::Validation(_) | yaoaic::Error::EmptyApiKey => {
+                            ("usage", None)
+                        }
+                    })
+                } else if let Some(prompts::Error::LoadError(_)) = c.downcast_ref() {
+                    Some(("network", None))
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(("usage", None));
+        Self {
+            error_kind,
+            message: format!("{e:#}"),
+            code,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let user_prompts = format!("{}/.config/yaoaic/prompts.csv", env!("HOME"));
-    let sources: &[prompts::Source] = &[
-        prompts::Source::Http(
-             "https://raw.githubusercontent.com/f/awesome-chatgpt-prompts/main/prompts.csv",
-        ),
-        prompts::Source::File(&user_prompts),
-        //prompts::Source::File("~/.local/cache/yaoaic/prompts.csv"),
-    ];
-    let args = Cli::parse();
-    let cache_dir = format!("{}/.local/share/yaoaic", env!("HOME"));
+    // clap reports its own errors, the flag is therefore also checked on the raw arguments
+    let json_errors = std::env::args().any(|a| a == "--json-errors");
+    let args = match Cli::try_parse() {
+        Ok(args) => args,
+        Err(e) if json_errors && e.use_stderr() => {
+            let error = JsonError {
+                error_kind: "usage",
+                message: e.render().to_string().trim().to_owned(),
+                code: None,
+            };
+            eprintln!("{}", serde_json::to_string(&error)?);
+            std::process::exit(2);
+        }
+        Err(e) => e.exit(),
+    };
+    match run(args).await {
+        Err(e) if json_errors => {
+            eprintln!("{}", serde_json::to_string(&JsonError::from(&e))?);
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
+
+async fn run(args: Cli) -> Result<()> {
+    let home = std::env::var("HOME")
+        .context("HOME is not set, unable to locate the config and the cache")?;
+    let user_prompts = format!("{home}/.config/yaoaic/prompts.csv");
+    let config = config::load(format!("{home}/.config/yaoaic/config.toml")).await?;
+    let prompts_urls = if !args.prompts_urls.is_empty() {
+        args.prompts_urls.clone()
+    } else if !config.prompts_urls.is_empty() {
+        config.prompts_urls.clone()
+    } else {
+        vec![DEFAULT_PROMPTS_URL.to_owned()]
+    };
+    let mut sources: Vec<_> = prompts_urls
+        .iter()
+        .map(|u| prompts::Source::Http(u))
+        .collect();
+    sources.push(prompts::Source::File(&user_prompts));
+    //prompts::Source::File("~/.local/cache/yaoaic/prompts.csv"),
+    sources.extend(args.sources.iter().map(|s| prompts::Source::from_arg(s)));
+    let raw_prompts = args.raw_prompts.as_ref().map(|r| r.replace("\\n", "\n"));
+    if let Some(r) = &raw_prompts {
+        sources.push(prompts::Source::Raw(r.as_bytes()));
+    }
+    let sources = &sources[..];
+    if args.verbose {
+        let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("yaoaic=debug"));
+        tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(std::io::stderr)
+            .init();
+    }
+    let cache_dir = format!("{home}/.local/share/yaoaic/{}", cache::SCHEMA_VERSION);
 
     let c = {
         if args.cache {
-            Some(cache::init(
-                &cache_dir,
-                Duration::new(args.cache_timeout_second, 0),
-            )?)
+            Some(
+                cache::init(
+                    &cache_dir,
+                    Duration::new(args.cache_timeout_second, 0),
+                    args.cache_format,
+                )?
+                .read_only(args.no_cache_write)
+                .keep(read_regardless_of_age),
+            )
         } else {
             None
         }
     };
-    let api_key = env!("OPENAI_API_KEY");
-    let client = OpenAIClient::new(api_key, Default::default());
+    let history_dir = format!("{cache_dir}/history");
+    let history = match &c {
+        Some(_) => Some(
+            cache::init(
+                &history_dir,
+                Duration::new(args.cache_timeout_second, 0),
+                args.cache_format,
+            )?
+            .read_only(args.no_cache_write),
+        ),
+        None => None,
+    };
+    if let Some(c) = &c {
+        if let Err(e) = c.prune().await {
+            eprintln!("warning: unable to prune cache: {e}");
+        }
+    }
+    // the flag takes precedence over the environment and the config
+    let model = args
+        .model
+        .clone()
+        .or_else(|| std::env::var("YAOAIC_MODEL").ok().filter(|m| !m.is_empty()))
+        .or_else(|| config.defaults.model.clone());
+    let mut params = Query {
+        model: model
+            .as_deref()
+            .map(|m| resolve_model(m, &config.models))
+            .transpose()?
+            .unwrap_or_default(),
+        top_p: args.top_p.or(config.defaults.top_p).unwrap_or(0.5),
+        max_tokens: match args.max_completion_tokens {
+            Some(_) => None,
+            None => args.max_tokens.or(config.defaults.max_tokens),
+        },
+        max_completion_tokens: args.max_completion_tokens,
+        temperature: args.temperature.or(config.defaults.temperature),
+        ..Default::default()
+    };
+    params.validate()?;
+    let api_key = std::env::var("OPENAI_API_KEY").unwrap_or_default();
+    let mut client_options = yaoaic::ClientOptions::default();
+    let mut fetch_options = prompts::FetchOptions::default();
+    if let Some(timeout) = args.timeout_seconds {
+        client_options.timeout = Duration::from_secs(timeout);
+        fetch_options.timeout = Duration::from_secs(timeout);
+    }
+    let client = OpenAIClient::with_options(api_key, Default::default(), &client_options);
     let mut messages: Vec<Message> = vec![];
+    let mut count_tokens = false;
+    if args.continue_conversation {
+        let c = c.as_ref().context("--continue requires the cache")?;
+        let last: Vec<Message> = c
+            .load_stale(conversation::LAST_MESSAGES)
+            .await
+            .context("no conversation to continue")?;
+        messages.extend(last.into_iter().filter(|m| args.resume_mode.keeps(m)));
+    }
     match args.cmd {
         Some(AdditionalCmd::Prompt { cmd }) => {
-            let all_prompts = {
-                match &c {
-                    Some(c) => c.with_cached("prompts.toml", sources, valid_prompts).await,
-                    None => valid_prompts(sources).await,
-                }?
+            let source_names: Vec<_> = sources.iter().map(|s| s.to_string()).collect();
+            let prompts_file = format!(
+                "{}_prompts",
+                cache::key_of(&(source_names, args.max_prompts))?
+            );
+            // only loaded by the commands working on all prompts
+            let all_prompts = async {
+                match c.as_ref() {
+                    Some(c) => {
+                        c.get_or_insert_with(&prompts_file, || {
+                            valid_prompts(Some(c), sources, args.max_prompts, &fetch_options)
+                        })
+                        .await
+                    }
+                    uncached => {
+                        valid_prompts(uncached, sources, args.max_prompts, &fetch_options).await
+                    }
+                }
             };
-            match cmd {
-                PromptCommands::List { filter } => {
-                    let filter = filter.map(|e| e.to_lowercase()).unwrap_or_default();
-                    for (i, p) in all_prompts.iter().enumerate() {
-                        if p.act.to_lowercase().contains(&filter)
-                            || p.prompt.to_lowercase().contains(&filter)
-                        {
-                            println!("{i}: {}", p.act);
+            let prompt = match cmd {
+                PromptCommands::List {
+                    filter: Some(query),
+                    rank: true,
+                    category,
+                    ..
+                } => {
+                    let all_prompts = prompts::PromptSet::from(all_prompts.await?);
+                    for (i, _) in prompts::rank_prompts(all_prompts.as_slice(), &query) {
+                        if in_category(&all_prompts[i], category.as_deref()) {
+                            println!("{i}: {}", all_prompts[i].act);
                         }
                     }
                     return Ok(());
                 }
-                PromptCommands::Select { option } => {
-                    let indexed: Option<usize> = match option.parse::<usize>() {
-                        Ok(x) => Some(x),
-                        Err(_) => None,
-                    };
-                    let prompt = all_prompts.into_iter().enumerate().find(|(i, p)| {
-                        if let Some(wi) = indexed {
-                            wi == *i
-                        } else {
-                            p.act == option
+                PromptCommands::List {
+                    filter,
+                    category,
+                    sort,
+                    ..
+                } => {
+                    let all_prompts = prompts::PromptSet::from(all_prompts.await?);
+                    let mut listed: Vec<_> = all_prompts
+                        .filter(&filter.unwrap_or_default())
+                        .filter(|(_, p)| in_category(p, category.as_deref()))
+                        .collect();
+                    match sort {
+                        Some(SortOrder::Act) => {
+                            listed.sort_by_cached_key(|(_, p)| p.act.to_lowercase())
                         }
+                        Some(SortOrder::Index) => listed.sort_by_key(|(i, _)| *i),
+                        Some(SortOrder::Length) => listed.sort_by_key(|(_, p)| p.prompt.len()),
+                        None => {}
+                    }
+                    for (i, p) in listed {
+                        println!("{i}: {}", p.act);
+                    }
+                    return Ok(());
+                }
+                PromptCommands::Export { format, dedupe } => {
+                    let deduped = prompts::dedupe(all_prompts.await?, dedupe.as_strategy());
+                    match format {
+                        ExportFormat::Json => {
+                            println!("{}", serde_json::to_string_pretty(&deduped)?)
+                        }
+                        ExportFormat::Csv => prompts::write_csv(&deduped, std::io::stdout())?,
+                    }
+                    return Ok(());
+                }
+                PromptCommands::Diff => {
+                    let (remote, local): (Vec<_>, Vec<_>) = sources
+                        .iter()
+                        .cloned()
+                        .partition(|s| matches!(s, prompts::Source::Http(_)));
+                    let remote =
+                        valid_prompts(c.as_ref(), &remote, args.max_prompts, &fetch_options)
+                            .await?;
+                    let local =
+                        valid_prompts(c.as_ref(), &local, args.max_prompts, &fetch_options).await?;
+                    let diff = prompts::diff_prompts(&remote, &local);
+                    for act in &diff.added {
+                        println!("+ {act}");
+                    }
+                    for act in &diff.removed {
+                        println!("- {act}");
+                    }
+                    for act in &diff.changed {
+                        println!("~ {act}");
+                    }
+                    println!(
+                        "{} added, {} removed, {} changed",
+                        diff.added.len(),
+                        diff.removed.len(),
+                        diff.changed.len()
+                    );
+                    return Ok(());
+                }
+                PromptCommands::Select { option } => prompts::PromptSet::from(all_prompts.await?)
+                    .find(&option)
+                    .cloned(),
+                PromptCommands::Edit { option } => {
+                    let all_prompts = prompts::PromptSet::from(all_prompts.await?);
+                    let mut p = all_prompts
+                        .find(&option)
+                        .cloned()
+                        .with_context(|| format!("no prompt found for {option}"))?;
+                    let vars = args.vars.iter().cloned().collect();
+                    p.prompt = edit(&p.render(&vars))?;
+                    Some(p)
+                }
+                PromptCommands::Random { filter, run, seed } => {
+                    let all_prompts = prompts::PromptSet::from(all_prompts.await?);
+                    let mut rng = match seed {
+                        Some(seed) => StdRng::seed_from_u64(seed),
+                        None => StdRng::from_entropy(),
+                    };
+                    let p = all_prompts
+                        .filter(&filter.unwrap_or_default())
+                        .map(|(_, p)| p.clone())
+                        .choose(&mut rng)
+                        .context("no prompt matches the filter")?;
+                    if !run {
+                        println!("{}\n\n{}", p.act, p.prompt);
+                        return Ok(());
+                    }
+                    Some(p)
+                }
+            };
+            if let Some(p) = prompt {
+                let vars = args.vars.iter().cloned().collect();
+                let rendered = prompts::Prompt {
+                    prompt: p.render(&vars),
+                    ..p
+                };
+                let q = Query {
+                    messages: Query::from(&rendered).messages,
+                    ..params.clone()
+                };
+                let cfn = format!("{}_messages", cache::key_of(&q)?);
+                let r = match &c {
+                    Some(c) => c.get_or_insert_with(&cfn, || ask((&q, &client))).await,
+                    None => ask((&q, &client)).await,
+                }?;
+                messages.extend(r);
+            }
+        }
+        Some(AdditionalCmd::Batch {
+            file,
+            concurrency,
+            output,
+        }) => {
+            let content = fs::read_to_string(&file)
+                .with_context(|| format!("unable to load batch file {file}"))?;
+            let persona = match &args.prompt_file {
+                Some(path) => Some(
+                    fs::read_to_string(path)
+                        .with_context(|| format!("unable to load prompt file {path}"))?,
+                ),
+                None => None,
+            };
+            let (lines, queries): (Vec<_>, Vec<_>) = content
+                .lines()
+                .enumerate()
+                .filter(|(_, l)| !l.trim().is_empty())
+                .map(|(i, l)| {
+                    let mut messages = vec![];
+                    if let Some(persona) = &persona {
+                        messages.push(Message {
+                            role: "system".to_owned(),
+                            content: persona.trim().to_owned(),
+                        });
+                    }
+                    if let Some(system) = &args.system {
+                        messages.push(Message {
+                            role: "system".to_owned(),
+                            content: system.clone(),
+                        });
+                    }
+                    messages.push(Message {
+                        content: l.trim().to_owned(),
+                        ..Default::default()
                     });
-                    if let Some((i, p)) = prompt {
-                        let cfn = format!("{i}_messages.toml");
-                        let mut prompt_msg = Message::default();
-                        prompt_msg.content = p.prompt;
-                        let mut q = Query::default();
-                        q.messages = vec![prompt_msg];
-                        let r = match &c {
-                            Some(c) => c.with_cached(&cfn, (&q, &client), ask).await,
-                            None => ask((&q, &client)).await,
-                        }?;
-                        messages.extend(r);
+                    let q = Query {
+                        messages: yaoaic::merge_system_messages(messages),
+                        ..params.clone()
+                    };
+                    (i + 1, q)
+                })
+                .unzip();
+            if let BatchOutput::Ndjson = output {
+                let mut responses =
+                    std::pin::pin!(client.send_queries_unordered(&queries, concurrency));
+                let mut stdout = std::io::stdout().lock();
+                while let Some((i, r)) = responses.next().await {
+                    let line = match &r {
+                        Ok(r) => BatchLine {
+                            index: lines[i],
+                            content: r.content(),
+                            usage: Some(&r.usage),
+                            error: None,
+                        },
+                        Err(e) => BatchLine {
+                            index: lines[i],
+                            content: None,
+                            usage: None,
+                            error: Some(e.to_string()),
+                        },
+                    };
+                    serde_json::to_writer(&mut stdout, &line)?;
+                    writeln!(stdout)?;
+                    stdout.flush()?;
+                }
+                return Ok(());
+            }
+            let responses = client.send_queries(&queries, concurrency).await;
+            for (line, r) in lines.into_iter().zip(responses) {
+                match r {
+                    Ok(r) => {
+                        if let Some(content) = r.content() {
+                            println!("{line}: {content}");
+                        }
                     }
+                    Err(e) => eprintln!("{line}: error: {e}"),
+                }
+            }
+            return Ok(());
+        }
+        Some(AdditionalCmd::Tokens { model }) => {
+            count_tokens = true;
+            if let Some(model) = model {
+                params.model = resolve_model(&model, &config.models)?;
+            }
+        }
+        Some(AdditionalCmd::Cache {
+            cmd: CacheCommands::List,
+        }) => {
+            let c = c.as_ref().context("the cache is disabled")?;
+            for e in c.entries().await? {
+                let expired = if e.expired { " (expired)" } else { "" };
+                println!("{}\t{}s{expired}", e.file_name, e.age.as_secs());
+            }
+            return Ok(());
+        }
+        Some(AdditionalCmd::History { cmd }) => {
+            let h = history.as_ref().context("the cache is disabled")?;
+            let names = conversation::history(h).await?;
+            match cmd {
+                HistoryCommands::List => {
+                    for (i, name) in names.iter().enumerate() {
+                        let messages: Vec<Message> = h.load_stale(name).await?;
+                        let first = messages
+                            .iter()
+                            .find(|m| m.role == "user")
+                            .and_then(|m| m.content.lines().next())
+                            .unwrap_or_default();
+                        println!("{i}: {first}");
+                    }
+                }
+                HistoryCommands::Show { n } => {
+                    let name = names
+                        .get(n)
+                        .with_context(|| format!("no conversation {n} in history"))?;
+                    let messages: Vec<Message> = h.load_stale(name).await?;
+                    transcript::print(&messages);
                 }
             }
+            return Ok(());
+        }
+        Some(AdditionalCmd::Ping) => {
+            client.ping().await?;
+            println!("ok");
+            return Ok(());
+        }
+        Some(AdditionalCmd::Models) => {
+            for m in client.list_models().await? {
+                println!("{}", m.id);
+            }
+            return Ok(());
         }
         None => {}
     };
 
     let input = {
+        let mut inputs = vec![];
+        if !args.stdin || args.append_stdin {
+            if args.input_files.is_empty() {
+                bail!("no input file given");
+            }
+            for f in &args.input_files {
+                inputs.push(
+                    fs::read_to_string(f).with_context(|| format!("unable to load file {f}"))?,
+                );
+            }
+        }
         if args.stdin {
-            std::io::stdin()
-                .lines()
-                .filter_map(|e| e.ok())
-                .collect::<Vec<String>>()
-                .join("")
-        } else {
-            fs::read_to_string(args.input_file.unwrap_or_default())
-                .context("unable to load file")?
+            inputs.push(
+                std::io::stdin()
+                    .lines()
+                    .map_while(Result::ok)
+                    .collect::<Vec<String>>()
+                    .join(""),
+            );
         }
+        inputs
+            .iter()
+            .map(|i| i.trim())
+            .collect::<Vec<_>>()
+            .join("\n\n")
     };
+    let echo = args.echo_input.then(|| input.clone());
     messages.push(Message {
-        content: input.trim().to_owned(),
+        content: input,
         ..Default::default()
     });
+    if let Some(system) = args.system {
+        messages.insert(
+            0,
+            Message {
+                role: "system".to_owned(),
+                content: system,
+            },
+        );
+    }
+    if let Some(path) = &args.prompt_file {
+        let persona = fs::read_to_string(path)
+            .with_context(|| format!("unable to load prompt file {path}"))?;
+        messages.insert(
+            0,
+            Message {
+                role: "system".to_owned(),
+                content: persona.trim().to_owned(),
+            },
+        );
+    }
     let q = Query {
-        model: args.model.unwrap_or_default().as_yaoic_model(),
-        top_p: args.top_p,
-        max_tokens: args.max_tokens,
-
-        messages,
+        messages: yaoaic::merge_system_messages(messages),
+        ..params
     };
+    if count_tokens {
+        let tokens = q.estimated_tokens();
+        println!("{tokens}");
+        if tokens > q.model.max_tokens() {
+            eprintln!(
+                "warning: exceeds the context of {} tokens",
+                q.model.max_tokens()
+            );
+        }
+        return Ok(());
+    }
 
-    let response = client.send_query(&q).await?;
-    if let Some(r) = response.choices.first() {
-        println!("{}", r.message.content)
+    // stored on drop when the query fails or is interrupted
+    let mut conversation = conversation::Conversation::new(c.as_ref(), q.messages.clone())
+        .max_messages(args.max_cached_messages)
+        .history(history.as_ref(), args.history);
+    if let Some(input) = echo {
+        println!("{input}\n---");
+    }
+    if args.stream {
+        if !q.model.is_chat() {
+            bail!("--stream requires a chat model");
+        }
+        let mut content = String::new();
+        let result = tokio::select! {
+            r = stream(&client, &q, &mut content) => r,
+            _ = tokio::signal::ctrl_c() => {
+                eprintln!("\ninterrupted");
+                Ok(())
+            }
+        };
+        // caches whatever was received, also when interrupted or failed
+        if !content.is_empty() {
+            conversation.extend([Message {
+                role: "assistant".to_owned(),
+                content,
+            }]);
+        }
+        conversation.flush().await?;
+        return result;
     }
-    if let Some(c) = c {
-        let mut cache_messages = q.messages.clone();
-        cache_messages.extend(response.choices.into_iter().map(|c| c.message));
-        let cached: cache::Value<Vec<Message>> = cache_messages.into();
-        c.store_cache("last_messages.toml", cached).await?;
+    let response = tokio::select! {
+        r = client.send_to_model(&q) => r?,
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("interrupted");
+            return Ok(());
+        }
+    };
+    tracing::debug!(model = ?response.model, usage = ?response.usage, "answered");
+    if response.best_choice().is_some_and(|c| c.is_truncated()) {
+        eprintln!("warning: the answer was cut off, consider increasing --max-tokens");
+    }
+    if args.pretty {
+        let mut transcript = q.messages.clone();
+        transcript.extend(response.best_choice().map(|c| c.message.clone()));
+        transcript::print(&transcript);
+    } else if let Some(template) = &args.template {
+        for choice in &response.choices {
+            println!("{}", transcript::render(template, &response, choice));
+        }
+    } else if let Some(content) = response.content() {
+        println!("{content}")
     }
+    conversation.extend(response.choices.into_iter().map(|c| c.message));
+    conversation.flush().await?;
 
     Ok(())
 }
@@ -16,6 +16,20 @@ where
         toml::to_string_pretty(&to_cache).context("unable to wrote cached prompts toml")?;
     let mut file = File::create(path).await?;
     file.write_all(cached_toml.as_bytes()).await?;
+    tracing::debug!(bytes = cached_toml.len(), "stored");
+    Ok(())
+}
+
+/// Like `replace` but blocks, e.g. to store within `Drop`.
+pub fn replace_blocking<P, T>(path: P, to_cache: T) -> Result<()>
+where
+    P: AsRef<Path>,
+    T: serde::ser::Serialize,
+{
+    let cached =
+        toml::to_string_pretty(&to_cache).context("unable to wrote cached prompts toml")?;
+    std::fs::write(path, &cached)?;
+    tracing::debug!(bytes = cached.len(), "stored");
     Ok(())
 }
 
@@ -34,6 +48,7 @@ where
     f.read_to_string(&mut cached)
         .await
         .context("unable to load into string")?;
+    tracing::debug!(bytes = cached.len(), "loaded");
     let cached: T = toml::from_str(&cached).with_context(|| {
         format!(
             "{} has unknown format.",
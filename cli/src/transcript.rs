@@ -0,0 +1,67 @@
+//! Prints a conversation with role labels or the answers by a template.
+use std::io::IsTerminal;
+
+use owo_colors::OwoColorize;
+use yaoaic::{Choice, Message, Response};
+
+/// Prints each message prefixed by its role.
+///
+/// The roles are colored when stdout is a terminal.
+pub fn print(messages: &[Message]) {
+    let colored = std::io::stdout().is_terminal();
+    for m in messages {
+        let role = format!("{}:", m.role);
+        let role = match m.role.as_str() {
+            _ if !colored => role,
+            "system" => role.yellow().bold().to_string(),
+            "assistant" => role.green().bold().to_string(),
+            "user" => role.blue().bold().to_string(),
+            _ => role.bold().to_string(),
+        };
+        println!("{role} {}", m.content);
+    }
+}
+
+/// Renders the template for a choice of the response.
+///
+/// Supported placeholders are `{content}`, `{role}`, `{index}`, `{model}` and `{total_tokens}`;
+/// a literal `\n` is replaced by a newline.
+pub fn render(template: &str, response: &Response, choice: &Choice) -> String {
+    template
+        .replace("\\n", "\n")
+        .replace("{role}", &choice.message.role)
+        .replace("{index}", &choice.index.to_string())
+        .replace("{model}", response.model.as_deref().unwrap_or_default())
+        .replace("{total_tokens}", &response.usage.total_tokens.to_string())
+        // last so that placeholders within the content are kept
+        .replace("{content}", &choice.message.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_choices() {
+        let response: Response = serde_json::from_str(
+            r#"{"id":"1","object":"chat.completion","created":0,"model":"gpt-4o-2024-05-13",
+            "usage":{"prompt_tokens":3,"completion_tokens":4,"total_tokens":7},
+            "choices":[
+                {"index":0,"finish_reason":"stop","message":{"role":"assistant","content":"first"}},
+                {"index":1,"finish_reason":"stop","message":{"role":"assistant","content":"{role}"}}
+            ]}"#,
+        )
+        .unwrap();
+        let template = "## {role} {index} ({model}, {total_tokens})\\n{content}";
+        let rendered: Vec<_> = response
+            .choices
+            .iter()
+            .map(|c| render(template, &response, c))
+            .collect();
+        assert_eq!(
+            rendered.join("\n"),
+            "## assistant 0 (gpt-4o-2024-05-13, 7)\nfirst\n\
+             ## assistant 1 (gpt-4o-2024-05-13, 7)\n{role}"
+        );
+    }
+}
@@ -1,11 +1,21 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
 
-use hyper::{body::Bytes, Body, Client, Request, Uri};
+use futures::StreamExt;
+use hyper::{body::Bytes, client::HttpConnector, Body, Client, Request, StatusCode, Uri};
 use hyper_tls::HttpsConnector;
 
 use serde::{Deserialize, Serialize};
 /// A message sent to the API.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Message {
     /// The role of the message.
     pub role: String,
@@ -32,21 +42,52 @@ pub enum Model {
     /// The code-davinci model.
     #[serde(rename = "code-davinci-002")]
     CodeDavinci,
+    /// Any other chat model by its id, e.g. a fine-tuned model.
+    #[serde(untagged)]
+    Other(String),
 }
 
 impl Model {
+    /// Returns true when the model is served by the chat completions endpoint.
+    ///
+    /// Other models must be sent via `OpenAIClient::send_completion`.
+    pub fn is_chat(&self) -> bool {
+        match self {
+            Model::GPT35Turbo => true,
+            Model::CodeDavinci => false,
+            Model::Other(_) => true,
+        }
+    }
+
     /// The maximum number of tokens the model can handle.
+    ///
+    /// For other models the limit is unknown, the smallest limit of 4096 is assumed.
     pub fn max_tokens(&self) -> usize {
         match self {
             Model::GPT35Turbo => 4096,
             Model::CodeDavinci => 8001,
+            Model::Other(_) => 4096,
+        }
+    }
+}
+
+impl FromStr for Model {
+    type Err = Error;
+
+    /// Parses the model id or the short name as used by the cli, e.g. `gpt-3.5-turbo` or
+    /// `gpt35-turbo`; unknown names are parsed as `Model::Other`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err(Error::Validation("model must not be empty".into())),
+            "gpt-3.5-turbo" | "gpt35-turbo" => Ok(Model::GPT35Turbo),
+            "code-davinci-002" | "code-davinci" => Ok(Model::CodeDavinci),
+            other => Ok(Model::Other(other.to_owned())),
         }
     }
 }
 
 /// A query to the API.
-#[derive(Clone, Deserialize, Serialize)]
-#[derive(Default)]
+#[derive(Clone, Deserialize, Serialize, Default)]
 pub struct Query {
     /// The model to use.
     pub model: Model,
@@ -54,14 +95,141 @@ pub struct Query {
     pub messages: Vec<Message>,
     /// The top-p value.
     pub top_p: f32,
-    /// The maximum number of tokens to use.
+    /// The maximum number of tokens to use, the legacy name of `max_completion_tokens`.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<usize>,
+    /// The maximum number of tokens to generate, newer models (e.g. the o-series) only accept
+    /// this name and reject `max_tokens`.
+    ///
+    /// Only one of both may be set, see `validate`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<usize>,
+    /// The sampling temperature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Whether to return the log probabilities of the output tokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    /// The amount of most likely tokens to return per position, requires `logprobs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u8>,
+}
+
+/// Merges consecutive system messages into one, their contents are joined with newlines.
+///
+/// Identical contents within a run of system messages are only kept once, as well as a content
+/// equal to what is merged so far, e.g. a previously merged message of a continued conversation.
+/// User and assistant messages are kept as they are.
+pub fn merge_system_messages(messages: Vec<Message>) -> Vec<Message> {
+    let mut merged: Vec<Message> = Vec::with_capacity(messages.len());
+    // the whole contents merged into the last message
+    let mut seen: Vec<String> = vec![];
+    for m in messages {
+        match merged.last_mut() {
+            Some(last) if last.role == "system" && m.role == "system" => {
+                if m.content != last.content && !seen.contains(&m.content) {
+                    last.content.push('\n');
+                    last.content.push_str(&m.content);
+                    seen.push(m.content);
+                }
+            }
+            _ => {
+                seen.clear();
+                seen.push(m.content.clone());
+                merged.push(m);
+            }
+        }
+    }
+    merged
 }
 
+/// Estimates the amount of tokens of a text.
+///
+/// This is a heuristic of roughly four characters per token and not an exact tokenization.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+impl Query {
+    /// Estimates the amount of prompt tokens of the messages.
+    ///
+    /// Besides the content each message is counted with an overhead of four tokens for the role
+    /// and separators.
+    pub fn estimated_tokens(&self) -> usize {
+        self.messages
+            .iter()
+            .map(|m| estimate_tokens(&m.content) + 4)
+            .sum()
+    }
+
+    /// Appends the message of the best choice of the response, ready for the next turn.
+    ///
+    /// Returns false when the response has no choice with index 0.
+    pub fn push_response(&mut self, response: &Response) -> bool {
+        match response.best_choice() {
+            Some(c) => {
+                self.messages.push(c.message.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops the oldest non-system messages until the prompt fits into the context of the model.
+    ///
+    /// `reserve_for_completion` tokens are kept free for the answer. System messages and the
+    /// latest message are never dropped, so the query may still exceed the limit afterwards.
+    /// Returns the amount of dropped messages.
+    pub fn truncate_messages_to_fit(&mut self, reserve_for_completion: usize) -> usize {
+        let limit = self
+            .model
+            .max_tokens()
+            .saturating_sub(reserve_for_completion);
+        let mut dropped = 0;
+        while self.estimated_tokens() > limit {
+            let last = self.messages.len().saturating_sub(1);
+            match self.messages[..last]
+                .iter()
+                .position(|m| m.role != "system")
+            {
+                Some(i) => {
+                    self.messages.remove(i);
+                    dropped += 1;
+                }
+                None => break,
+            }
+        }
+        dropped
+    }
 
+    /// Verifies that the sampling parameters are within the ranges accepted by the API.
+    ///
+    /// `top_p` must be within [0, 1] and `temperature` within [0, 2].
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(0.0..=1.0).contains(&self.top_p) {
+            return Err(Error::Validation(format!(
+                "top_p must be between 0 and 1 but is {}",
+                self.top_p
+            )));
+        }
+        if let Some(t) = self.temperature {
+            if !(0.0..=2.0).contains(&t) {
+                return Err(Error::Validation(format!(
+                    "temperature must be between 0 and 2 but is {t}"
+                )));
+            }
+        }
+        if self.max_tokens.is_some() && self.max_completion_tokens.is_some() {
+            return Err(Error::Validation(
+                "only one of max_tokens and max_completion_tokens may be set".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
 
 /// The usage of the API.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Usage {
     /// The number of tokens used by the prompt.
     pub prompt_tokens: usize,
@@ -71,6 +239,57 @@ pub struct Usage {
     pub total_tokens: usize,
 }
 
+/// Accumulates the usage over multiple responses.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UsageAccumulator {
+    /// The number of tokens used by the prompts.
+    pub prompt_tokens: usize,
+    /// The number of tokens used by the completions.
+    pub completion_tokens: usize,
+    /// The total number of tokens used.
+    pub total_tokens: usize,
+    /// Is true when at least one of the added usages is an estimate.
+    pub estimated: bool,
+}
+
+impl UsageAccumulator {
+    /// Adds the usage reported by the API.
+    pub fn add(&mut self, usage: &Usage) {
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.total_tokens += usage.total_tokens;
+    }
+
+    /// Adds an estimated usage for a query and its completion text.
+    ///
+    /// Is meant for streamed responses that do not report a usage; the numbers are an estimate
+    /// based on `estimate_tokens` and the accumulator is marked as `estimated`.
+    pub fn add_estimated(&mut self, query: &Query, completion: &str) {
+        let prompt_tokens = query.estimated_tokens();
+        let completion_tokens = estimate_tokens(completion);
+        self.prompt_tokens += prompt_tokens;
+        self.completion_tokens += completion_tokens;
+        self.total_tokens += prompt_tokens + completion_tokens;
+        self.estimated = true;
+    }
+}
+
+/// Decompresses the body based on the `Content-Encoding` of the response.
+///
+/// Unknown encodings are returned as they are.
+fn decompress(encoding: Option<&str>, bytes: Bytes) -> std::io::Result<Bytes> {
+    use std::io::Read;
+    let mut decoded = Vec::new();
+    match encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+        Some("gzip") | Some("x-gzip") => {
+            flate2::read::GzDecoder::new(&bytes[..]).read_to_end(&mut decoded)?
+        }
+        Some("deflate") => flate2::read::ZlibDecoder::new(&bytes[..]).read_to_end(&mut decoded)?,
+        _ => return Ok(bytes),
+    };
+    Ok(decoded.into())
+}
+
 /// The response from the API.
 #[derive(Debug, Deserialize)]
 pub struct Response {
@@ -80,19 +299,44 @@ pub struct Response {
     pub object: String,
     /// The time the response was created.
     pub created: u32,
+    /// The model that actually answered, e.g. a dated snapshot of the requested model.
+    pub model: Option<String>,
     /// The usage of the API.
     pub usage: Usage,
     /// The choices of the response.
     pub choices: Vec<Choice>,
-    /// The context of the response.
-    pub context: Option<String>,
+    /// The `X-Request-Id` the client sent with the query, to correlate it with server logs.
+    #[serde(skip)]
+    pub request_id: Option<String>,
+}
+impl Response {
+    /// Returns the choice with index 0.
+    pub fn best_choice(&self) -> Option<&Choice> {
+        self.choices.iter().find(|c| c.index == 0)
+    }
+
+    /// Returns the message content of the first choice.
+    pub fn content(&self) -> Option<&str> {
+        self.best_choice().map(|c| c.message.content.as_str())
+    }
+
+    /// Returns the message contents of all choices.
+    pub fn contents(&self) -> Vec<&str> {
+        self.choices
+            .iter()
+            .map(|c| c.message.content.as_str())
+            .collect()
+    }
 }
+
 /// Enum representing the reasons for stopping token generation by the API.
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum FinishReason {
     /// The API stopped generating tokens because it reached the maximum length or because it encountered a stop token.
     Stop,
+    /// The API stopped generating tokens because it reached `max_tokens` or the context length.
+    Length,
     /// The API stopped generating tokens because it exceeded the maximum time allowed for a response.
     Timeout,
     /// The API successfully generated a response and completed the prompt.
@@ -112,24 +356,433 @@ pub struct Choice {
     pub finish_reason: FinishReason,
     /// The index of the choice.
     pub index: usize,
+    /// The log probabilities of the choice when requested via `Query::logprobs`.
+    pub logprobs: Option<LogProbs>,
+}
+
+impl Choice {
+    /// Returns true when the message was cut off because it reached the token limit.
+    pub fn is_truncated(&self) -> bool {
+        matches!(self.finish_reason, FinishReason::Length)
+    }
+}
+
+/// The response from the legacy completions endpoint.
+#[derive(Debug, Deserialize)]
+pub struct CompletionResponse {
+    /// The ID of the response.
+    pub id: String,
+    /// The object of the response.
+    pub object: String,
+    /// The time the response was created.
+    pub created: u32,
+    /// The model that actually answered.
+    pub model: Option<String>,
+    /// The usage of the API.
+    pub usage: Usage,
+    /// The choices of the response.
+    pub choices: Vec<CompletionChoice>,
+}
+
+impl CompletionResponse {
+    /// Returns the text of the choice with index 0.
+    pub fn text(&self) -> Option<&str> {
+        self.choices
+            .iter()
+            .find(|c| c.index == 0)
+            .map(|c| c.text.as_str())
+    }
+}
+
+/// Converts the completions into assistant messages, to handle both endpoints alike.
+impl From<CompletionResponse> for Response {
+    fn from(value: CompletionResponse) -> Self {
+        Self {
+            id: value.id,
+            object: value.object,
+            created: value.created,
+            model: value.model,
+            usage: value.usage,
+            choices: value
+                .choices
+                .into_iter()
+                .map(|c| Choice {
+                    message: Message {
+                        role: "assistant".to_owned(),
+                        content: c.text,
+                    },
+                    finish_reason: c.finish_reason,
+                    index: c.index,
+                    logprobs: None,
+                })
+                .collect(),
+            request_id: None,
+        }
+    }
+}
+
+/// A choice of the completions response.
+#[derive(Debug, Deserialize)]
+pub struct CompletionChoice {
+    /// The completed text.
+    pub text: String,
+    /// The reason the choice was finished.
+    pub finish_reason: FinishReason,
+    /// The index of the choice.
+    pub index: usize,
+}
+
+/// A chunk of a streamed chat completion, see `OpenAIClient::send_query_stream`.
+#[derive(Debug, Deserialize)]
+pub struct StreamChunk {
+    /// The ID of the response, it is the same for all chunks.
+    pub id: String,
+    /// The model that was used.
+    pub model: Option<String>,
+    /// The deltas of the choices.
+    pub choices: Vec<StreamChoice>,
+}
+
+impl StreamChunk {
+    /// Returns the content delta of the first choice.
+    pub fn content(&self) -> Option<&str> {
+        self.choices.first()?.delta.content.as_deref()
+    }
+}
+
+/// The delta of a choice within a `StreamChunk`.
+#[derive(Debug, Deserialize)]
+pub struct StreamChoice {
+    /// The index of the choice.
+    pub index: usize,
+    /// The added part of the message.
+    pub delta: Delta,
+}
+
+/// A part of a message, the role is only set within the first chunk.
+#[derive(Debug, Default, Deserialize)]
+pub struct Delta {
+    /// The role of the message.
+    pub role: Option<String>,
+    /// The added content.
+    pub content: Option<String>,
+}
+
+/// The body of a streamed chat completion request.
+#[derive(Serialize)]
+struct StreamBody<'a> {
+    #[serde(flatten)]
+    query: &'a Query,
+    stream: bool,
+}
+
+/// A line of a server-sent event stream.
+#[derive(Debug)]
+enum EventLine {
+    /// Empty lines, comments and other fields than `data`.
+    Ignored,
+    /// The final `data: [DONE]`.
+    Done,
+    Chunk(Result<StreamChunk, Error>),
+}
+
+impl EventLine {
+    fn parse(line: &[u8]) -> Self {
+        let line = String::from_utf8_lossy(line);
+        let Some(data) = line.trim_end().strip_prefix("data:") else {
+            return Self::Ignored;
+        };
+        match data.trim_start() {
+            "[DONE]" => Self::Done,
+            data => Self::Chunk(OpenAIClient::parse(data.as_bytes())),
+        }
+    }
+}
+
+/// Splits the body of a server-sent event stream into chunks.
+fn events<S, E>(body: S) -> impl futures::Stream<Item = Result<StreamChunk, Error>>
+where
+    S: futures::Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+{
+    futures::stream::unfold(
+        (body, Vec::new(), false),
+        |(mut body, mut buf, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                if let Some(end) = buf.iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = buf.drain(..=end).collect();
+                    match EventLine::parse(&line) {
+                        EventLine::Ignored => continue,
+                        EventLine::Done => return None,
+                        EventLine::Chunk(chunk) => return Some((chunk, (body, buf, false))),
+                    }
+                }
+                match body.next().await {
+                    Some(Ok(bytes)) => buf.extend_from_slice(&bytes),
+                    Some(Err(e)) => {
+                        return Some((Err(Error::Unknown(e.to_string())), (body, buf, true)))
+                    }
+                    None => return None,
+                }
+            }
+        },
+    )
+}
+
+/// A query to the legacy completions endpoint.
+///
+/// Contains the parameters that only exist for completions, chat queries use `Query`.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct CompletionQuery {
+    /// The model to use, see `Model::is_chat`.
+    pub model: Model,
+    /// The prompt to complete.
+    pub prompt: String,
+    /// The maximum number of tokens to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    /// The sampling temperature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// The top-p value.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// Generates `best_of` completions server side and returns the best one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<usize>,
+    /// Whether to echo the prompt in addition to the completion.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo: Option<bool>,
+}
+
+impl CompletionQuery {
+    /// Creates a query for the prompt without optional parameters.
+    pub fn new(prompt: impl Into<String>, model: Model) -> Self {
+        Self {
+            model,
+            prompt: prompt.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Converts a chat query for a completion model, the message contents are joined into one prompt.
+impl From<&Query> for CompletionQuery {
+    fn from(q: &Query) -> Self {
+        let prompt = q
+            .messages
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        Self {
+            max_tokens: q.max_tokens.or(q.max_completion_tokens),
+            temperature: q.temperature,
+            top_p: Some(q.top_p),
+            ..Self::new(prompt, q.model.clone())
+        }
+    }
+}
+
+/// The log probabilities of a choice.
+#[derive(Debug, Deserialize)]
+pub struct LogProbs {
+    /// The log probabilities of the message content tokens.
+    pub content: Option<Vec<TokenLogProb>>,
+}
+
+/// The log probability of a token.
+#[derive(Debug, Deserialize)]
+pub struct TokenLogProb {
+    /// The token.
+    pub token: String,
+    /// The log probability of the token.
+    pub logprob: f64,
+    /// The UTF-8 bytes of the token.
+    pub bytes: Option<Vec<u8>>,
+    /// The most likely tokens at this position.
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogProb>,
+}
+
+/// One of the most likely tokens at a position.
+#[derive(Debug, Deserialize)]
+pub struct TopLogProb {
+    /// The token.
+    pub token: String,
+    /// The log probability of the token.
+    pub logprob: f64,
+    /// The UTF-8 bytes of the token.
+    pub bytes: Option<Vec<u8>>,
+}
+
+/// A model available via the API.
+#[derive(Debug, Deserialize)]
+pub struct ModelInfo {
+    /// The ID of the model.
+    pub id: String,
+    /// The time the model was created.
+    pub created: Option<u64>,
+    /// The organization owning the model.
+    pub owned_by: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+/// The connection options of the API client.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Uses HTTP/2 exclusively; the server must support HTTP/2.
+    pub http2_only: bool,
+    /// How long idle connections are kept alive, `None` keeps them forever.
+    pub pool_idle_timeout: Option<Duration>,
+    /// The maximum amount of idle connections per host.
+    pub pool_max_idle_per_host: usize,
+    /// How failed requests are retried.
+    pub retry: RetryPolicy,
+    /// How long a single request may take including reading the response.
+    pub timeout: Duration,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            http2_only: false,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            pool_max_idle_per_host: usize::MAX,
+            retry: RetryPolicy::default(),
+            timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Defines how requests failing with a transport error, `429` or a server error are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The maximum amount of retries per request, 0 disables retries.
+    pub max_retries: u32,
+    /// The delay before the first retry, it is doubled for each further retry.
+    pub base_delay: Duration,
+    /// After this many consecutive failed requests retries are skipped until a request succeeds.
+    pub circuit_breaker_threshold: u64,
+    /// Randomizes the delays so that clients failing at the same time don't retry in sync.
+    pub jitter: Jitter,
+}
+
+/// How the delay of a retry is randomized.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Jitter {
+    /// Uses the exponential delay as is.
+    None,
+    /// Picks a delay between 0 and the exponential delay.
+    Full,
+    /// Picks a delay between half and the whole exponential delay.
+    #[default]
+    Equal,
+}
+
+/// Returns a random number in `[0, 1)` based on the random bits of an uuid v4.
+fn random() -> f64 {
+    // the lower 53 bits contain neither the version nor the variant
+    let bits = uuid::Uuid::new_v4().as_u128() as u64 & ((1 << 53) - 1);
+    bits as f64 / (1u64 << 53) as f64
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            base_delay: Duration::from_millis(500),
+            circuit_breaker_threshold: 5,
+            jitter: Jitter::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the given retry, starting with 0, without jitter.
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.base_delay.saturating_mul(1 << retry.min(16))
+    }
+
+    /// The delay before the given retry with the jitter applied.
+    ///
+    /// `random` returns a number in `[0, 1)`, it is only called when a jitter is configured.
+    pub fn jittered_delay(&self, retry: u32, random: impl FnOnce() -> f64) -> Duration {
+        let delay = self.delay(retry);
+        let random = || random().clamp(0.0, 1.0);
+        match self.jitter {
+            Jitter::None => delay,
+            Jitter::Full => delay.mul_f64(random()),
+            Jitter::Equal => delay / 2 + (delay / 2).mul_f64(random()),
+        }
+    }
+}
+
+/// Counters of the requests of a client.
+#[derive(Debug, Default)]
+pub struct Stats {
+    attempts: AtomicU64,
+    retries: AtomicU64,
+    failures: AtomicU64,
+    consecutive_failures: AtomicU64,
+}
+
+impl Stats {
+    /// The amount of sent requests, including retries.
+    pub fn attempts(&self) -> u64 {
+        self.attempts.load(Ordering::Relaxed)
+    }
+
+    /// The amount of retries.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// The amount of requests that still failed after all retries.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// The amount of failed requests since the last successful one.
+    pub fn consecutive_failures(&self) -> u64 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
 }
 
 /// The API client.
-pub struct OpenAIClient<'a> {
+pub struct OpenAIClient {
     client: Client<HttpsConnector<hyper::client::HttpConnector>>,
-    api_key: &'a str,
+    api_key: String,
     url: Uri,
+    retry: RetryPolicy,
+    timeout: Duration,
+    stats: Stats,
 }
 
 #[derive(Default)]
 pub enum OpenAIUri {
     #[default]
     ChatCompletion,
+    /// The legacy completions endpoint, e.g. for code-davinci.
+    Completions,
+    Models,
 }
 
 #[derive(Debug)]
 pub enum Error {
     Api(ApiError),
+    Validation(String),
+    /// The api key is empty, requests would be rejected as unauthorized.
+    EmptyApiKey,
+    /// The api key was rejected by the API.
+    Unauthorized,
     Unknown(String),
 }
 
@@ -147,20 +800,54 @@ impl Display for Error {
             Error::Api(e) => write!(
                 f,
                 "Error response: {} {}: {}",
-                e.code, e.error_type, e.message
+                e.code().unwrap_or_default(),
+                e.error_type,
+                e.message
             ),
+            Error::Validation(a) => write!(f, "Invalid query: {a}"),
+            Error::EmptyApiKey => write!(f, "API key is empty"),
+            Error::Unauthorized => write!(f, "API key was rejected"),
             Error::Unknown(a) => write!(f, "{a}"),
         }
     }
 }
 
+/// An error returned by the API.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ApiError {
     message: String,
     #[serde(rename = "type")]
     error_type: String,
     param: Option<String>,
-    code: String,
+    code: Option<String>,
+}
+
+impl ApiError {
+    /// The human readable description of the error.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The type of the error, e.g. `invalid_request_error`.
+    pub fn error_type(&self) -> &str {
+        &self.error_type
+    }
+
+    /// The parameter of the query that caused the error.
+    pub fn param(&self) -> Option<&str> {
+        self.param.as_deref()
+    }
+
+    /// The code of the error, e.g. `rate_limit_exceeded`.
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+}
+
+/// The API wraps errors within an `error` field.
+#[derive(Deserialize)]
+struct ApiErrorResponse {
+    error: ApiError,
 }
 
 impl OpenAIUri {
@@ -172,48 +859,930 @@ impl OpenAIUri {
                     Err(_) => unreachable!("Hard coded uri must be parseable"),
                 }
             }
+            OpenAIUri::Completions => match "https://api.openai.com/v1/completions".parse() {
+                Ok(x) => x,
+                Err(_) => unreachable!("Hard coded uri must be parseable"),
+            },
+            OpenAIUri::Models => match "https://api.openai.com/v1/models".parse() {
+                Ok(x) => x,
+                Err(_) => unreachable!("Hard coded uri must be parseable"),
+            },
         }
     }
 }
 
-impl<'a> OpenAIClient<'a> {
+impl OpenAIClient {
+    /// Returns the url of the endpoint at `path`, e.g. `/models`.
+    ///
+    /// The path replaces the endpoint of the configured url so that a base url given via
+    /// `with_base_url` is used for all endpoints.
+    fn endpoint(&self, path: &str) -> Uri {
+        let current = self.url.path();
+        let base = ["/chat/completions", "/completions", "/models"]
+            .iter()
+            .find_map(|e| current.strip_suffix(e))
+            .unwrap_or(current);
+        let mut parts = self.url.clone().into_parts();
+        parts.path_and_query = match format!("{base}{path}").parse() {
+            Ok(x) => Some(x),
+            Err(_) => unreachable!("a valid path with a hard coded suffix must be parseable"),
+        };
+        match Uri::from_parts(parts) {
+            Ok(x) => x,
+            Err(_) => unreachable!("the parts of a valid uri must be valid"),
+        }
+    }
+
     /// Create a new API client.
-    pub fn new(api_key: &'a str, url: OpenAIUri) -> Self {
-        let https = HttpsConnector::new();
-        let client = Client::builder().build(https);
+    pub fn new(api_key: impl Into<String>, url: OpenAIUri) -> Self {
+        Self::with_options(api_key, url, &Default::default())
+    }
+
+    /// Create a new API client with the given connection options.
+    pub fn with_options(
+        api_key: impl Into<String>,
+        url: OpenAIUri,
+        options: &ClientOptions,
+    ) -> Self {
+        let client = Client::builder()
+            .http2_only(options.http2_only)
+            .pool_idle_timeout(options.pool_idle_timeout)
+            .pool_max_idle_per_host(options.pool_max_idle_per_host)
+            .build(connector());
 
         Self {
             client,
-            api_key,
+            api_key: api_key.into(),
             url: url.as_uri(),
+            retry: options.retry.clone(),
+            timeout: options.timeout,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Create a new API client for a custom completions url, e.g. of a proxy.
+    ///
+    /// The url must use http or https and point at a `/chat/completions` or `/completions`
+    /// endpoint; surrounding whitespace and a trailing slash are removed. The other endpoints,
+    /// e.g. the models, are derived from it.
+    pub fn with_base_url(
+        api_key: impl Into<String>,
+        url: &str,
+        options: &ClientOptions,
+    ) -> Result<Self, Error> {
+        let url = Self::validate_url(url)?;
+        let mut client = Self::with_options(api_key, OpenAIUri::default(), options);
+        client.url = url;
+        Ok(client)
+    }
+
+    fn validate_url(url: &str) -> Result<Uri, Error> {
+        let normalized = url.trim().trim_end_matches('/');
+        let invalid = |reason: &str| Error::Validation(format!("invalid url {url}: {reason}"));
+        let uri: Uri = normalized.parse().map_err(|e| invalid(&format!("{e}")))?;
+        match uri.scheme_str() {
+            Some("http") | Some("https") => {}
+            _ => return Err(invalid("scheme must be http or https")),
+        }
+        if uri.host().is_none() {
+            return Err(invalid("host is missing"));
+        }
+        if !uri.path().ends_with("/completions") {
+            return Err(invalid("path must point at a completions endpoint"));
+        }
+        Ok(uri)
+    }
+
+    /// Returns the request counters of this client.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns true when the status is worth a retry and counts as failure of the API.
+    fn is_retryable(status: StatusCode) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+    }
+
+    /// Counts a request that still failed after all retries, or resets the consecutive failures.
+    fn record(&self, failed: bool) {
+        if failed {
+            self.stats.failures.fetch_add(1, Ordering::Relaxed);
+            self.stats
+                .consecutive_failures
+                .fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.stats.consecutive_failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Runs the request until it succeeded or the retry policy gives up.
+    ///
+    /// The body of the last response is returned so that error responses are parsed as
+    /// `Error::Api`.
+    async fn with_retries<F, Fut>(&self, mut request: F) -> Result<Bytes, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(StatusCode, Bytes), Box<dyn std::error::Error>>>,
+    {
+        let mut retry = 0;
+        loop {
+            self.stats.attempts.fetch_add(1, Ordering::Relaxed);
+            let result = request().await.map_err(|e| e.to_string());
+            let retryable = match &result {
+                Ok((status, _)) => Self::is_retryable(*status),
+                Err(_) => true,
+            };
+            if retryable {
+                let circuit_open =
+                    self.stats.consecutive_failures() >= self.retry.circuit_breaker_threshold;
+                if retry < self.retry.max_retries && !circuit_open {
+                    self.stats.retries.fetch_add(1, Ordering::Relaxed);
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(retry, "retrying request");
+                    tokio::time::sleep(self.retry.jittered_delay(retry, random)).await;
+                    retry += 1;
+                    continue;
+                }
+            }
+            self.record(retryable);
+            return result.map(|(_, bytes)| bytes).map_err(Error::Unknown);
         }
     }
 
-    async fn send<Q>(&self, q: Q) -> Result<Bytes, Box<dyn std::error::Error>>
+    async fn send<Q>(
+        &self,
+        url: Uri,
+        q: Q,
+        request_id: &str,
+    ) -> Result<(StatusCode, Bytes), Box<dyn std::error::Error>>
     where
         Q: Serialize,
     {
         let req = Request::builder()
             .method("POST")
-            .uri(self.url.clone())
+            .uri(url)
             .header("Content-Type", "application/json")
+            .header("X-Request-Id", request_id)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .body(Body::from(serde_json::to_string(&q)?))?;
+        self.execute(req).await
+    }
 
-        let res = self.client.request(req).await?;
-        hyper::body::to_bytes(res.into_body())
+    async fn get(&self, url: Uri) -> Result<(StatusCode, Bytes), Box<dyn std::error::Error>> {
+        let req = Request::builder()
+            .method("GET")
+            .uri(url)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .body(Body::empty())?;
+        self.execute(req).await
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(url = %req.uri())))]
+    async fn execute(
+        &self,
+        mut req: Request<Body>,
+    ) -> Result<(StatusCode, Bytes), Box<dyn std::error::Error>> {
+        req.headers_mut().insert(
+            hyper::header::ACCEPT_ENCODING,
+            hyper::header::HeaderValue::from_static("gzip, deflate"),
+        );
+        tokio::time::timeout(self.timeout, self.read(req))
             .await
-            .map_err(|e| e.into())
+            .map_err(|_| format!("request timed out after {:?}", self.timeout))?
+    }
+
+    /// Sends the request and reads the decompressed body.
+    async fn read(
+        &self,
+        req: Request<Body>,
+    ) -> Result<(StatusCode, Bytes), Box<dyn std::error::Error>> {
+        let res = self.client.request(req).await?;
+        let status = res.status();
+        #[cfg(feature = "tracing")]
+        tracing::debug!(%status, "received response");
+        let encoding = res
+            .headers()
+            .get(hyper::header::CONTENT_ENCODING)
+            .and_then(|e| e.to_str().ok())
+            .map(|e| e.to_owned());
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(bytes = bytes.len(), encoding, "read response body");
+        Ok((status, decompress(encoding.as_deref(), bytes)?))
+    }
+
+    /// Returns `Error::EmptyApiKey` when there is no api key to spare a pointless request.
+    fn verify_api_key(&self) -> Result<(), Error> {
+        if self.api_key.trim().is_empty() {
+            return Err(Error::EmptyApiKey);
+        }
+        Ok(())
+    }
+
+    /// Deserializes the bytes, with the `error-path` feature the error contains the failing field.
+    fn deserialize<T>(bytes: &[u8]) -> Result<T, String>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        #[cfg(feature = "error-path")]
+        let result =
+            serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(bytes));
+        #[cfg(not(feature = "error-path"))]
+        let result = serde_json::from_slice(bytes);
+        result.map_err(|e| e.to_string())
+    }
+
+    /// Parses the bytes into T or into an Error::Api when it is an error response.
+    fn parse<T>(bytes: &[u8]) -> Result<T, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        Self::deserialize(bytes).map_err(|e| {
+            match serde_json::from_slice::<ApiErrorResponse>(bytes) {
+                Ok(r) => Error::Api(r.error),
+                Err(_) => match serde_json::from_slice::<ApiError>(bytes) {
+                    Ok(r) => Error::Api(r),
+                    Err(_) => Error::Unknown(e),
+                },
+            }
+        })
     }
 
     /// Send a query to the API.
+    ///
+    /// Each call sends a new UUID as `X-Request-Id`, it is returned in `Response::request_id`.
+    ///
+    /// Dropping the returned future cancels the request, so it can be raced e.g. against a
+    /// shutdown signal:
+    ///
+    /// ```no_run
+    /// # async fn example(client: yaoaic::OpenAIClient, q: yaoaic::Query) {
+    /// tokio::select! {
+    ///     r = client.send_query(&q) => println!("{:?}", r.map(|r| r.id)),
+    ///     _ = tokio::signal::ctrl_c() => println!("cancelled"),
+    /// }
+    /// # }
+    /// ```
     pub async fn send_query(&self, q: &Query) -> Result<Response, Error> {
-        let bytes = self.send(q).await?;
-        serde_json::from_slice(&bytes).map_err(|e| {
-            match serde_json::from_slice::<ApiError>(&bytes) {
-                Ok(r) => Error::Api(r),
-                Err(_) => Error::Unknown(e.to_string()),
-            }
+        self.verify_api_key()?;
+        let request_id = uuid::Uuid::new_v4().to_string();
+        #[cfg(feature = "tracing")]
+        tracing::debug!(request_id, "sending query");
+        let bytes = self
+            .with_retries(|| self.send(self.url.clone(), q, &request_id))
+            .await?;
+        let mut response: Response = Self::parse(&bytes)?;
+        response.choices.sort_by_key(|c| c.index);
+        response.request_id = Some(request_id);
+        Ok(response)
+    }
+
+    /// Sends a query and blocks until the response arrived.
+    ///
+    /// The query runs on a new current thread runtime, therefore this must not be called from
+    /// within an async context.
+    #[cfg(feature = "blocking")]
+    pub fn send_query_blocking(&self, q: &Query) -> Result<Response, Error> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| Error::Unknown(e.to_string()))?
+            .block_on(self.send_query(q))
+    }
+
+    /// Sends the prompt to the legacy completions endpoint.
+    ///
+    /// Models that are not chat models, see `Model::is_chat`, are only served by this endpoint.
+    pub async fn send_completion(
+        &self,
+        prompt: &str,
+        model: Model,
+    ) -> Result<CompletionResponse, Error> {
+        self.send_completion_query(&CompletionQuery::new(prompt, model))
+            .await
+    }
+
+    /// Sends the query as streamed request and returns the chunks as they arrive.
+    ///
+    /// The stream ends after the final event of the API. Streamed requests are not retried and the
+    /// timeout only applies until the response headers are received. Failures until then count
+    /// for the circuit breaker like failures of other requests.
+    pub async fn send_query_stream(
+        &self,
+        q: &Query,
+    ) -> Result<impl futures::Stream<Item = Result<StreamChunk, Error>>, Error> {
+        self.verify_api_key()?;
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let body = serde_json::to_string(&StreamBody {
+            query: q,
+            stream: true,
         })
+        .map_err(|e| Error::Unknown(e.to_string()))?;
+        let req = Request::builder()
+            .method("POST")
+            .uri(self.url.clone())
+            .header("Content-Type", "application/json")
+            .header("X-Request-Id", &request_id)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .body(Body::from(body))
+            .map_err(|e| Error::Unknown(e.to_string()))?;
+        self.stats.attempts.fetch_add(1, Ordering::Relaxed);
+        let res = tokio::time::timeout(self.timeout, self.client.request(req))
+            .await
+            .map_err(|_| format!("request timed out after {:?}", self.timeout))
+            .and_then(|r| r.map_err(|e| e.to_string()));
+        self.record(
+            res.as_ref()
+                .map_or(true, |r| Self::is_retryable(r.status())),
+        );
+        let res = res.map_err(Error::Unknown)?;
+        if !res.status().is_success() {
+            let bytes = hyper::body::to_bytes(res.into_body())
+                .await
+                .map_err(|e| Error::Unknown(e.to_string()))?;
+            return Err(match Self::parse::<ApiErrorResponse>(&bytes) {
+                Ok(r) => Error::Api(r.error),
+                Err(e) => e,
+            });
+        }
+        Ok(events(res.into_body()))
+    }
+
+    /// Sends the query to the legacy completions endpoint.
+    pub async fn send_completion_query(
+        &self,
+        q: &CompletionQuery,
+    ) -> Result<CompletionResponse, Error> {
+        self.verify_api_key()?;
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let url = self.endpoint("/completions");
+        let bytes = self
+            .with_retries(|| self.send(url.clone(), q, &request_id))
+            .await?;
+        Self::parse(&bytes)
+    }
+
+    /// Sends an arbitrary JSON body to the API and returns the raw JSON response.
+    ///
+    /// This is an escape hatch for parameters that `Query` does not support yet. Error responses
+    /// are still returned as `Error::Api`.
+    pub async fn send_raw_json(&self, body: serde_json::Value) -> Result<serde_json::Value, Error> {
+        self.verify_api_key()?;
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let bytes = self
+            .with_retries(|| self.send(self.url.clone(), &body, &request_id))
+            .await?;
+        if let Ok(r) = serde_json::from_slice::<ApiErrorResponse>(&bytes) {
+            return Err(Error::Api(r.error));
+        }
+        Self::parse(&bytes)
+    }
+
+    /// Sends the query to the endpoint serving its model.
+    ///
+    /// Chat models are sent via `send_query`, other models via `send_completion_query` with the
+    /// query converted into a `CompletionQuery`.
+    pub async fn send_to_model(&self, q: &Query) -> Result<Response, Error> {
+        if q.model.is_chat() {
+            return self.send_query(q).await;
+        }
+        let mut response: Response = self.send_completion_query(&q.into()).await?.into();
+        response.choices.sort_by_key(|c| c.index);
+        Ok(response)
+    }
+
+    /// Sends multiple queries with at most `concurrency` requests in flight.
+    ///
+    /// Each query is sent to the endpoint serving its model, see `send_to_model`. The results are
+    /// in the same order as the queries.
+    pub async fn send_queries(
+        &self,
+        queries: &[Query],
+        concurrency: usize,
+    ) -> Vec<Result<Response, Error>> {
+        futures::stream::iter(queries)
+            .map(|q| self.send_to_model(q))
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Sends multiple queries with at most `concurrency` requests in flight.
+    ///
+    /// Each query is sent to the endpoint serving its model, see `send_to_model`. The results are
+    /// yielded as soon as they arrive, together with the index of the query.
+    pub fn send_queries_unordered<'s>(
+        &'s self,
+        queries: &'s [Query],
+        concurrency: usize,
+    ) -> impl futures::Stream<Item = (usize, Result<Response, Error>)> + 's {
+        futures::stream::iter(queries.iter().enumerate())
+            .map(move |(i, q)| async move { (i, self.send_to_model(q).await) })
+            .buffer_unordered(concurrency.max(1))
+    }
+
+    /// Verifies the api key and the connectivity without spending tokens.
+    ///
+    /// Sends a single request to the models endpoint, a rejected api key results in
+    /// `Error::Unauthorized`.
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.verify_api_key()?;
+        let (status, bytes) = self.get(self.endpoint("/models")).await?;
+        match status {
+            s if s.is_success() => Ok(()),
+            StatusCode::UNAUTHORIZED => Err(Error::Unauthorized),
+            s => match Self::parse::<ModelList>(&bytes) {
+                Err(e @ Error::Api(_)) => Err(e),
+                _ => Err(Error::Unknown(format!("unexpected status {s}"))),
+            },
+        }
+    }
+
+    /// Lists the models available for the used api key.
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>, Error> {
+        self.verify_api_key()?;
+        let bytes = self
+            .with_retries(|| self.get(self.endpoint("/models")))
+            .await?;
+        let models: ModelList = Self::parse(&bytes)?;
+        Ok(models.data)
+    }
+}
+
+/// Returns the https connector shared by all clients.
+///
+/// Each `OpenAIClient::with_options` builds its own hyper client for the given pool options,
+/// only the TLS setup of the connector is reused.
+fn connector() -> HttpsConnector<HttpConnector> {
+    static CONNECTOR: OnceLock<HttpsConnector<HttpConnector>> = OnceLock::new();
+    CONNECTOR.get_or_init(HttpsConnector::new).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_api_error() {
+        let body = br#"{"error": {"message": "Rate limit reached", "type": "requests", "param": null, "code": "rate_limit_exceeded"}}"#;
+        match OpenAIClient::parse::<Response>(body) {
+            Err(Error::Api(e)) => {
+                assert_eq!(e.code(), Some("rate_limit_exceeded"));
+                assert_eq!(e.error_type(), "requests");
+                assert_eq!(e.param(), None);
+            }
+            r => panic!("expected api error but got {r:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_logprobs() {
+        let body = br#"{"id": "1", "object": "chat.completion", "created": 1,
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3},
+            "choices": [
+                {"index": 0, "finish_reason": "stop",
+                    "message": {"role": "assistant", "content": "Hi!"},
+                    "logprobs": {"content": [
+                        {"token": "Hi", "logprob": -0.31, "bytes": [72, 105],
+                            "top_logprobs": [
+                                {"token": "Hi", "logprob": -0.31, "bytes": [72, 105]},
+                                {"token": "Hello", "logprob": -1.4, "bytes": null}]},
+                        {"token": "!", "logprob": -0.02, "bytes": [33], "top_logprobs": []}]}},
+                {"index": 1, "finish_reason": "stop",
+                    "message": {"role": "assistant", "content": "Hi"}, "logprobs": null}]}"#;
+        let response = OpenAIClient::parse::<Response>(body).unwrap();
+        let content = response.choices[0]
+            .logprobs
+            .as_ref()
+            .and_then(|l| l.content.as_ref())
+            .unwrap();
+        assert_eq!(content.len(), 2);
+        assert_eq!(content[0].token, "Hi");
+        assert_eq!(content[0].bytes.as_deref(), Some(&b"Hi"[..]));
+        let top: Vec<_> = content[0].top_logprobs.iter().map(|t| &t.token).collect();
+        assert_eq!(top, ["Hi", "Hello"]);
+        assert_eq!(content[0].top_logprobs[1].bytes, None);
+        assert!(content[1].top_logprobs.is_empty());
+        assert!(response.choices[1].logprobs.is_none());
+    }
+
+    #[cfg(feature = "error-path")]
+    #[test]
+    fn parse_error_contains_path() {
+        let body = br#"{"id": "1", "object": "chat.completion", "created": 1,
+            "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2},
+            "choices": [{"index": 0, "finish_reason": "unknown",
+                "message": {"role": "assistant", "content": "hi"}}]}"#;
+        match OpenAIClient::parse::<Response>(body) {
+            Err(Error::Unknown(e)) => assert!(e.starts_with("choices[0].finish_reason"), "{e}"),
+            r => panic!("expected unknown error but got {r:?}"),
+        }
+    }
+
+    #[test]
+    fn truncate_messages_to_fit() {
+        let message = |role: &str, content: String| Message {
+            role: role.to_string(),
+            content,
+        };
+        let mut query = Query {
+            messages: vec![
+                message("system", "be brief".into()),
+                message("user", "a".repeat(12000)),
+                message("assistant", "b".repeat(12000)),
+                message("user", "c".repeat(4000)),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(query.truncate_messages_to_fit(1000), 2);
+        assert_eq!(query.messages[0].role, "system");
+        assert_eq!(query.messages[1].content, "c".repeat(4000));
+        assert!(query.estimated_tokens() + 1000 <= query.model.max_tokens());
+    }
+
+    #[test]
+    fn decompress() {
+        use std::io::Write;
+        let json = br#"{"id":"1"}"#;
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(json).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut zlib = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+        zlib.write_all(json).unwrap();
+        let zlib = zlib.finish().unwrap();
+        for (encoding, bytes) in [
+            (Some("gzip"), gzip),
+            (Some("deflate"), zlib),
+            (None, json.to_vec()),
+        ] {
+            let decoded = super::decompress(encoding, bytes.into()).unwrap();
+            assert_eq!(&decoded[..], json);
+        }
+    }
+
+    #[test]
+    fn completion_query_skips_unset() {
+        let mut q = CompletionQuery::new("fn main", Model::CodeDavinci);
+        assert_eq!(
+            serde_json::to_value(&q).unwrap(),
+            serde_json::json!({"model": "code-davinci-002", "prompt": "fn main"})
+        );
+        q.best_of = Some(3);
+        q.echo = Some(true);
+        let json = serde_json::to_value(&q).unwrap();
+        assert_eq!(json["best_of"], 3);
+        assert_eq!(json["echo"], true);
+    }
+
+    #[test]
+    fn completion_query_from_query() {
+        let q = Query {
+            model: Model::CodeDavinci,
+            messages: ["fn main", "() {}"]
+                .map(|c| Message {
+                    content: c.into(),
+                    ..Default::default()
+                })
+                .to_vec(),
+            max_completion_tokens: Some(16),
+            ..Default::default()
+        };
+        let cq = CompletionQuery::from(&q);
+        assert_eq!(cq.prompt, "fn main\n\n() {}");
+        assert_eq!(cq.max_tokens, Some(16));
+        assert_eq!(cq.top_p, Some(q.top_p));
+    }
+
+    #[test]
+    fn merge_system_messages() {
+        let message = |role: &str, content: &str| Message {
+            role: role.into(),
+            content: content.into(),
+        };
+        let merged = super::merge_system_messages(vec![
+            message("system", "a"),
+            message("system", "b"),
+            message("system", "a"),
+            message("user", "q"),
+            message("system", "c"),
+        ]);
+        assert_eq!(
+            merged,
+            vec![
+                message("system", "a\nb"),
+                message("user", "q"),
+                message("system", "c"),
+            ]
+        );
+        let persona = "you are a poet\nanswer in rhymes";
+        let merged = super::merge_system_messages(vec![
+            message("system", persona),
+            message("system", "be brief"),
+            message("system", "answer in rhymes"),
+            message("system", persona),
+        ]);
+        let expected = format!("{persona}\nbe brief\nanswer in rhymes");
+        assert_eq!(merged, vec![message("system", &expected)]);
+        // the merged message of a continued conversation
+        let continued = format!("{persona}\nbe brief");
+        let merged = super::merge_system_messages(vec![
+            message("system", persona),
+            message("system", "be brief"),
+            message("system", &continued),
+        ]);
+        assert_eq!(merged, vec![message("system", &continued)]);
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let body = [
+            ": keep-alive\n\ndata: {\"id\":\"1\",\"choices\":[{\"index\":0,",
+            "\"delta\":{\"role\":\"assistant\",\"content\":\"Hel\"}}]}\n\n",
+            "data: {\"id\":\"1\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"lo\"}}]}\n\n",
+            "data: [DONE]\n\ndata: ignored\n\n",
+        ];
+        let body =
+            futures::stream::iter(body.map(|b| Ok::<_, std::convert::Infallible>(Bytes::from(b))));
+        let chunks: Vec<_> = super::events(body).collect().await;
+        let contents: Vec<_> = chunks
+            .iter()
+            .map(|c| c.as_ref().unwrap().content().unwrap())
+            .collect();
+        assert_eq!(contents, vec!["Hel", "lo"]);
+    }
+
+    #[test]
+    fn max_completion_tokens() {
+        let mut q = Query {
+            max_completion_tokens: Some(10),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&q).unwrap();
+        assert_eq!(json["max_completion_tokens"], 10);
+        assert!(json.get("max_tokens").is_none());
+        assert!(q.validate().is_ok());
+        q.max_tokens = Some(10);
+        assert!(matches!(q.validate(), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn validate_sampling_ranges() {
+        let valid = |top_p: f32, temperature: Option<f32>| {
+            let q = Query {
+                top_p,
+                temperature,
+                ..Default::default()
+            };
+            match q.validate() {
+                Ok(()) => true,
+                Err(Error::Validation(_)) => false,
+                Err(e) => panic!("unexpected error {e}"),
+            }
+        };
+        for top_p in [0.0, 0.5, 1.0] {
+            assert!(valid(top_p, None), "top_p {top_p}");
+        }
+        for top_p in [-0.01, 1.01, f32::NAN] {
+            assert!(!valid(top_p, None), "top_p {top_p}");
+        }
+        for temperature in [0.0, 1.0, 2.0] {
+            assert!(valid(1.0, Some(temperature)), "temperature {temperature}");
+        }
+        for temperature in [-0.01, 2.01, f32::NAN] {
+            assert!(!valid(1.0, Some(temperature)), "temperature {temperature}");
+        }
+    }
+
+    #[test]
+    fn push_response() {
+        let response: Response = serde_json::from_str(
+            r#"{"id":"1","object":"chat.completion","created":0,"usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2},"choices":[{"message":{"role":"assistant","content":"hi"},"finish_reason":"stop","index":0}]}"#,
+        )
+        .unwrap();
+        let mut q = Query::default();
+        assert!(q.push_response(&response));
+        assert_eq!(q.messages[0].role, "assistant");
+        assert_eq!(q.messages[0].content, "hi");
+    }
+
+    #[test]
+    fn is_truncated() {
+        let choice: Choice = serde_json::from_str(
+            r#"{"message":{"role":"assistant","content":"cut"},"finish_reason":"length","index":0}"#,
+        )
+        .unwrap();
+        assert!(choice.is_truncated());
+    }
+
+    #[test]
+    fn other_model() {
+        let model: Model = serde_json::from_str(r#""gpt-4o""#).unwrap();
+        assert!(matches!(&model, Model::Other(id) if id == "gpt-4o"));
+        assert_eq!(serde_json::to_string(&model).unwrap(), r#""gpt-4o""#);
+        let model: Model = serde_json::from_str(r#""gpt-3.5-turbo""#).unwrap();
+        assert!(matches!(model, Model::GPT35Turbo));
+    }
+
+    #[test]
+    fn model_from_str() {
+        assert!(matches!("gpt-3.5-turbo".parse(), Ok(Model::GPT35Turbo)));
+        assert!(matches!("code-davinci".parse(), Ok(Model::CodeDavinci)));
+        assert!(matches!("gpt-4o".parse(), Ok(Model::Other(id)) if id == "gpt-4o"));
+        assert!(" ".parse::<Model>().is_err());
+    }
+
+    #[test]
+    fn validate_url() {
+        let uri =
+            OpenAIClient::validate_url(" http://localhost:8080/v1/chat/completions/ ").unwrap();
+        assert_eq!(uri.to_string(), "http://localhost:8080/v1/chat/completions");
+        for url in [
+            "not a url",
+            "ftp://localhost/v1/completions",
+            "https://localhost/v1/models",
+        ] {
+            assert!(
+                matches!(OpenAIClient::validate_url(url), Err(Error::Validation(_))),
+                "{url}"
+            );
+        }
+    }
+
+    /// Answers a single request with the body and returns the request line, e.g. `GET /v1/models`.
+    async fn serve_once(body: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let request_line = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            let line = request.lines().next().unwrap_or_default();
+            line.rsplit_once(' ').map_or(line, |(l, _)| l).to_owned()
+        });
+        (
+            format!("http://{addr}/proxy/v1/chat/completions"),
+            request_line,
+        )
+    }
+
+    fn proxied(url: &str) -> OpenAIClient {
+        OpenAIClient::with_base_url("key", url, &ClientOptions::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn completions_use_base_url() {
+        let (url, request_line) = serve_once(
+            r#"{"id":"1","object":"text_completion","created":0,"model":"code-davinci-002",
+            "usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2},
+            "choices":[{"text":"{}","finish_reason":"stop","index":0}]}"#,
+        )
+        .await;
+        let r = proxied(&url)
+            .send_completion("fn main()", Model::CodeDavinci)
+            .await
+            .unwrap();
+        assert_eq!(r.choices[0].text, "{}");
+        assert_eq!(request_line.await.unwrap(), "POST /proxy/v1/completions");
+    }
+
+    #[tokio::test]
+    async fn ping_uses_base_url() {
+        let (url, request_line) = serve_once(r#"{"data":[]}"#).await;
+        proxied(&url).ping().await.unwrap();
+        assert_eq!(request_line.await.unwrap(), "GET /proxy/v1/models");
+    }
+
+    #[tokio::test]
+    async fn list_models_uses_base_url() {
+        let (url, request_line) = serve_once(r#"{"data":[{"id":"gpt-4o"}]}"#).await;
+        let models = proxied(&url).list_models().await.unwrap();
+        assert_eq!(models[0].id, "gpt-4o");
+        assert_eq!(request_line.await.unwrap(), "GET /proxy/v1/models");
+    }
+
+    #[test]
+    fn retry_delay() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(400));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(100 << 16));
+    }
+
+    #[tokio::test]
+    async fn with_retries() {
+        let options = ClientOptions {
+            retry: RetryPolicy {
+                max_retries: 2,
+                base_delay: Duration::from_millis(10),
+                circuit_breaker_threshold: 2,
+                jitter: Jitter::None,
+            },
+            ..Default::default()
+        };
+        let client = OpenAIClient::with_options("key", Default::default(), &options);
+        let calls = std::cell::Cell::new(0);
+        let respond = |status: StatusCode| {
+            let calls = &calls;
+            move || {
+                calls.set(calls.get() + 1);
+                async move { Ok((status, Bytes::from_static(b"body"))) }
+            }
+        };
+        let stats = |c: &OpenAIClient| {
+            let s = c.stats();
+            (
+                s.attempts(),
+                s.retries(),
+                s.failures(),
+                s.consecutive_failures(),
+            )
+        };
+
+        // retried with a delay of 10ms and 20ms, then the last body is returned
+        let started = std::time::Instant::now();
+        let bytes = client
+            .with_retries(respond(StatusCode::SERVICE_UNAVAILABLE))
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert_eq!(bytes, "body");
+        assert_eq!(calls.get(), 3);
+        assert_eq!(stats(&client), (3, 2, 1, 1));
+
+        // the second failed request opens the circuit, further requests are not retried
+        client
+            .with_retries(respond(StatusCode::TOO_MANY_REQUESTS))
+            .await
+            .unwrap();
+        assert_eq!(stats(&client), (6, 4, 2, 2));
+        client
+            .with_retries(respond(StatusCode::INTERNAL_SERVER_ERROR))
+            .await
+            .unwrap();
+        assert_eq!(stats(&client), (7, 4, 3, 3));
+
+        // a success closes it again
+        client.with_retries(respond(StatusCode::OK)).await.unwrap();
+        assert_eq!(stats(&client), (8, 4, 3, 0));
+        let failing = || async { Err::<(StatusCode, Bytes), _>("connection refused".into()) };
+        let e = client.with_retries(failing).await.unwrap_err();
+        assert!(matches!(e, Error::Unknown(m) if m == "connection refused"));
+        assert_eq!(stats(&client), (11, 6, 4, 1));
+    }
+
+    #[test]
+    fn retry_jitter() {
+        let policy = |jitter| RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            jitter,
+            ..Default::default()
+        };
+        let equal = policy(Jitter::Equal);
+        assert_eq!(equal.jittered_delay(2, || 0.0), Duration::from_millis(200));
+        assert_eq!(equal.jittered_delay(2, || 0.5), Duration::from_millis(300));
+        let full = policy(Jitter::Full);
+        assert_eq!(full.jittered_delay(2, || 0.0), Duration::ZERO);
+        assert_eq!(full.jittered_delay(2, || 0.25), Duration::from_millis(100));
+        assert_eq!(full.jittered_delay(1, || 7.0), Duration::from_millis(200));
+        let none = policy(Jitter::None);
+        assert_eq!(
+            none.jittered_delay(1, || unreachable!()),
+            Duration::from_millis(200)
+        );
+        let r = random();
+        assert!((0.0..1.0).contains(&r));
+    }
+
+    #[test]
+    fn estimated_usage() {
+        let q = Query {
+            messages: vec![Message {
+                content: "12345678".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut usage = UsageAccumulator::default();
+        usage.add_estimated(&q, "12345");
+        assert_eq!(
+            usage,
+            UsageAccumulator {
+                prompt_tokens: 6,
+                completion_tokens: 2,
+                total_tokens: 8,
+                estimated: true,
+            }
+        );
     }
 }